@@ -1,114 +1,394 @@
-use abop_lib::cli::Opt;
+use abop_lib::analysis::Analysis;
+use abop_lib::catalog::Catalog;
+use abop_lib::cli::{Command, Opt, StatsFormat};
+use abop_lib::config::reset_config;
+use abop_lib::geometry::Mesh;
+#[cfg(feature = "viewer")]
 use abop_lib::graphics;
-use abop_lib::lsys::LSystem;
+use abop_lib::lsys::{LSystem, Selection};
 use abop_lib::parser;
+use abop_lib::stats::GenerationStats;
 use abop_lib::turtle::Turtle;
-use device_query::{DeviceQuery, DeviceState, Keycode};
+use abop_lib::{Error, Result};
 use std::fs;
-use std::io::{self, Read};
+use std::io::{self, BufRead, Read, Write};
+use std::process;
 use structopt::StructOpt;
 
 struct Application {
     options: Opt,
-    input: String,
+    catalog: Option<(Catalog, usize)>,
     lsystem: Option<LSystem>,
-    turtle: Option<Turtle>,
+    model: Option<Mesh>,
+    /// The generation to derive depth-first when streaming
+    streamed: Option<usize>,
 }
 
 impl Application {
-    fn new(options: Opt) -> Self {
-        let input = Self::read_input(&options);
+    fn new(options: Opt) -> Result<Self> {
         let mut app = Application {
             options,
-            input,
+            catalog: None,
             lsystem: None,
-            turtle: None,
+            model: None,
+            streamed: None,
         };
-        if app.options.use_lsystem() {
-            app.lsystem = Some(parser::parse_lsys(&app.input));
-        }
-        if app.options.use_turtle() {
-            app.turtle = Some(Turtle::new());
+        match &app.options.dir {
+            Some(dir) => {
+                let catalog = Catalog::read_dir(dir)?;
+                let selected = Self::choose(&catalog)?;
+                app.catalog = Some((catalog, selected));
+                app.load_selected()?;
+            }
+            None if app.options.interactive && app.options.file.is_none() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "interactive mode reads commands from STDIN, give the lsystem as FILE",
+                )
+                .into());
+            }
+            None => {
+                let input = Self::read_input(&app.options)?;
+                app.load(&input)?;
+            }
         }
-        app
+        Ok(app)
     }
 
-    fn run(mut self) {
+    fn load(&mut self, input: &str) -> Result<()> {
+        if self.options.input_graphics_model {
+            self.model = Some(input.parse()?);
+        } else if self.options.input_lstring {
+            self.lsystem = Some(LSystem::new(input.parse()?, Vec::new()));
+        } else {
+            self.lsystem = Some(parser::parse_lsys(input)?);
+        }
         if let Some(lsys) = &mut self.lsystem {
-            lsys.nth(self.options.iterations);
+            lsys.set_limits(self.options.limits()?);
+            if self.options.legacy_probabilities {
+                lsys.set_selection(Selection::Legacy)?;
+            }
+            for warning in lsys.validate() {
+                eprintln!("warning: {}", warning);
+            }
+            lsys.set_provenance(self.options.trace.is_some())?;
+        }
+        if self.options.stream {
+            self.streamed = Some(self.options.iterations);
+        }
+        if self.options.stats.is_some() || self.options.stream {
+            return Ok(());
         }
+        if let Some(lsys) = &mut self.lsystem {
+            if let Some(Err(e)) = lsys.nth(self.options.iterations) {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn load_selected(&mut self) -> Result<()> {
+        if let Some((catalog, selected)) = &self.catalog {
+            let entry = &catalog.entries()[*selected];
+            writeln!(io::stdout().lock(), "{}", entry)?;
+            let input = fs::read_to_string(&entry.path)?;
+            reset_config()?;
+            self.load(&input)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "viewer")]
+    fn select(&mut self, offset: isize) -> Result<()> {
+        if let Some((catalog, selected)) = &mut self.catalog {
+            *selected = (*selected as isize + offset).rem_euclid(catalog.len() as isize) as usize;
+        }
+        self.load_selected()
+    }
 
+    fn choose(catalog: &Catalog) -> Result<usize> {
+        if catalog.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no lsystem files found").into());
+        }
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        write!(out, "{}", catalog)?;
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            write!(out, "Choose a model by number or name: ")?;
+            out.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            };
+            match catalog.find(&line) {
+                Some(selected) => return Ok(selected),
+                None => writeln!(out, "No model matches '{}'", line.trim())?,
+            }
+        }
+    }
+
+    fn run(mut self) -> Result<()> {
         if self.options.use_graphics() {
-            graphics::Application::new(self).run();
+            self.render()
+        } else if let Some(format) = self.options.stats {
+            self.print_stats(format)
+        } else if let Some(module) = self.options.trace {
+            self.print_trace(module)
+        } else if self.options.interactive {
+            self.interact()
         } else {
-            'app: loop {
-                if let Some(turtle) = &mut self.turtle {
-                    if let Some(lsystem) = &self.lsystem {
-                        println!("{:?}", turtle.interpret(&lsystem.current)); // to do - Display for Model
-                    }
-                } else if let Some(lsystem) = &self.lsystem {
-                    match self.options.verbose {
-                        true => println!("{}", lsystem),
-                        false => println!("{}", lsystem.current),
-                    }
+            self.print()
+        }
+    }
+
+    fn print(&self) -> Result<()> {
+        let stdout = io::stdout();
+        let mut out = io::BufWriter::new(stdout.lock());
+        if self.options.output_model {
+            write!(out, "{}", self.model()?)?;
+        } else if let (Some(lsystem), Some(n)) = (&self.lsystem, self.streamed) {
+            for element in lsystem.stream(n)? {
+                write!(out, "{}", element?)?;
+            }
+            writeln!(out)?;
+        } else if let Some(lsystem) = &self.lsystem {
+            match self.options.verbose {
+                true => writeln!(out, "{}", lsystem)?,
+                false => writeln!(out, "{}", lsystem.current)?,
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Develops the lsystem up to the number of iterations, or a fixed point, writing
+    /// the statistics of each generation.
+    fn print_stats(&mut self, format: StatsFormat) -> Result<()> {
+        let lsystem = match &mut self.lsystem {
+            Some(lsystem) => lsystem,
+            None => return Ok(()),
+        };
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        if format == StatsFormat::Table {
+            writeln!(out, "{}", GenerationStats::HEADER)?;
+        }
+        loop {
+            let stats = lsystem.stats()?;
+            match format {
+                StatsFormat::Table => writeln!(out, "{}", stats)?,
+                StatsFormat::Json => writeln!(out, "{}", stats.to_json())?,
+            }
+            if stats.generation >= self.options.iterations || stats.fixed_point {
+                return Ok(());
+            }
+            lsystem.generate()?;
+        }
+    }
+
+    /// Writes the modules from which a module of the current generation was derived,
+    /// one generation per line, with the productions which rewrote them.
+    fn print_trace(&mut self, module: usize) -> Result<()> {
+        let lsystem = match &mut self.lsystem {
+            Some(lsystem) => lsystem,
+            None => return Ok(()),
+        };
+        lsystem.set_provenance(true)?;
+        let steps = match lsystem.provenance().and_then(|p| p.trace(module)) {
+            Some(steps) => steps,
+            None => {
+                eprintln!("There is no module {}", module);
+                return Ok(());
+            }
+        };
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        for step in steps {
+            let lstring = lsystem.generation(step.generation)?;
+            let element = lstring.get(step.index).unwrap();
+            let indent = "  ".repeat(step.generation);
+            match step.production {
+                Some(i) => writeln!(
+                    out,
+                    "{:>4} {}#{} {}  by {}",
+                    step.generation,
+                    indent,
+                    step.index,
+                    element,
+                    lsystem.productions()[i]
+                )?,
+                None => writeln!(
+                    out,
+                    "{:>4} {}#{} {}",
+                    step.generation, indent, step.index, element
+                )?,
+            }
+        }
+        Ok(())
+    }
+
+    fn interact(&mut self) -> Result<()> {
+        eprintln!("Type 'help' for a list of commands");
+        self.print()?;
+        self.prompt();
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line?.parse::<Command>() {
+                Ok(Command::Quit) => break,
+                Ok(command) => match self.execute(command) {
+                    Err(e) if is_broken_pipe(&e) => return Err(e),
+                    Err(e) => eprintln!("{}", e),
+                    Ok(()) => (),
+                },
+                Err(e) => eprintln!("{}", e),
+            }
+            self.prompt();
+        }
+        Ok(())
+    }
+
+    fn prompt(&self) {
+        if let Some(lsystem) = &self.lsystem {
+            eprint!("[{}]> ", lsystem.current_generation());
+        }
+    }
+
+    fn execute(&mut self, command: Command) -> Result<()> {
+        let lsystem = match &mut self.lsystem {
+            Some(lsystem) => lsystem,
+            None => return Ok(()),
+        };
+        match command {
+            Command::Next => lsystem.generate()?,
+            Command::Previous => {
+                if !lsystem.step_back()? {
+                    eprintln!("Already at the axiom");
+                    return Ok(());
                 }
+            }
+            Command::Goto(n) => lsystem.goto(n)?,
+            Command::Set(setting) => parser::parse_setting(&setting)?,
+            Command::Reseed(seed) => lsystem.reseed(seed.unwrap_or_else(rand::random))?,
+            Command::Trace(module) => return self.print_trace(module),
+            Command::Predict(n) => {
+                let analysis = Analysis::new(lsystem)?;
+                let stdout = io::stdout();
+                let mut out = stdout.lock();
+                writeln!(out, "length {}", analysis.length(n))?;
+                for (symbol, count) in analysis.counts(n) {
+                    writeln!(out, "{} {}", symbol, count)?;
+                }
+                return Ok(());
+            }
+            Command::Rules => {
+                let stdout = io::stdout();
+                let mut out = stdout.lock();
+                for production in lsystem.productions() {
+                    writeln!(out, "{}", production)?;
+                }
+                return Ok(());
+            }
+            Command::Help | Command::Quit => {
+                eprintln!("{}", Command::HELP);
+                return Ok(());
+            }
+        }
+        self.print()
+    }
 
-                if !self.options.interactive {
-                    break;
-                } else {
-                    println!("Press SPACE for next iteration, ESC to quit");
-                    let device_state = DeviceState::new();
-                    loop {
-                        let keys: Vec<Keycode> = device_state.get_keys();
-                        if keys.contains(&Keycode::Space) {
-                            if let Some(lsystem) = &mut self.lsystem {
-                                lsystem.generate();
-                                break;
-                            }
-                        }
-
-                        if keys.contains(&Keycode::Escape) {
-                            break 'app;
-                        }
-                    }
+    fn model(&self) -> Result<Mesh> {
+        match (&self.model, &self.lsystem) {
+            (Some(model), _) => Ok(model.clone()),
+            (None, Some(lsystem)) => {
+                let interpret = |turtle: &mut Turtle| match self.streamed {
+                    Some(n) => turtle.interpret_stream(lsystem.stream(n)?),
+                    None => turtle.interpret(&lsystem.current),
+                };
+                match self.options.max_triangles {
+                    Some(triangles) => Turtle::within_budget(triangles, interpret),
+                    None => interpret(&mut Turtle::new()?),
                 }
             }
+            (None, None) => Ok(Mesh::new()),
         }
     }
 
-    fn read_input(opt: &Opt) -> String {
+    #[cfg(feature = "viewer")]
+    fn render(self) -> Result<()> {
+        graphics::Application::new(self)?.run()
+    }
+
+    #[cfg(not(feature = "viewer"))]
+    fn render(self) -> Result<()> {
+        Err(io::Error::other(
+            "built without the viewer, use '-l' or '-m' to write the output as text",
+        )
+        .into())
+    }
+
+    fn read_input(opt: &Opt) -> Result<String> {
         match &opt.file {
             None => {
                 let mut buffer = String::new();
-                io::stdin().read_to_string(&mut buffer).unwrap();
-                buffer
+                io::stdin().read_to_string(&mut buffer)?;
+                Ok(buffer)
             }
-            Some(path) => fs::read_to_string(path).unwrap(),
+            Some(path) => Ok(fs::read_to_string(path)?),
         }
     }
 }
 
+/// True for the error of writing to a pipe whose reader has gone, as when the output
+/// is cut short by `head`
+fn is_broken_pipe(e: &Error) -> bool {
+    match e {
+        Error::Io(e) => e.kind() == io::ErrorKind::BrokenPipe,
+        _ => false,
+    }
+}
+
 fn main() {
-    Application::new(Opt::from_args()).run();
+    match Application::new(Opt::from_args()).and_then(Application::run) {
+        Err(e) if is_broken_pipe(&e) => (),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        Ok(()) => (),
+    }
 }
 
+#[cfg(feature = "viewer")]
 impl graphics::Displayable for Application {
-    fn vertices(&mut self) -> Vec<graphics::Vertex> {
-        if let Some(turtle) = &mut self.turtle {
-            if let Some(lsystem) = &mut self.lsystem {
-                turtle.interpret(&lsystem.current)
-            } else {
-                unimplemented!()
-            }
-        } else {
-            unimplemented!()
+    fn mesh(&mut self) -> Result<Mesh> {
+        self.model()
+    }
+
+    fn update(&mut self) -> Result<()> {
+        if let Some(n) = &mut self.streamed {
+            *n += 1;
+        } else if let Some(lsystem) = &mut self.lsystem {
+            lsystem.generate()?;
         }
+        Ok(())
     }
 
-    fn update(&mut self) {
-        if let Some(lsystem) = &mut self.lsystem {
-            lsystem.generate();
-            self.turtle = Some(Turtle::new());
+    fn step_back(&mut self) -> Result<()> {
+        if let Some(n) = &mut self.streamed {
+            *n = n.saturating_sub(1);
+        } else if let Some(lsystem) = &mut self.lsystem {
+            lsystem.step_back()?;
         }
+        Ok(())
+    }
+
+    fn next_model(&mut self) -> Result<()> {
+        self.select(1)
+    }
+
+    fn previous_model(&mut self) -> Result<()> {
+        self.select(-1)
     }
 }
@@ -46,7 +46,7 @@ fn lsys_benchmark(c: &mut Criterion, title: &str, data: &'static str, iters: usi
         title,
         move |b: &mut Bencher, n: &usize| {
             b.iter_batched(
-                move || parser::parse_lsys(data),
+                move || parser::parse_lsys(data).unwrap(),
                 |mut lsys| lsys.nth(black_box(*n)),
                 BatchSize::PerIteration,
             )
@@ -0,0 +1,283 @@
+use crate::error::{Error, Result};
+use crate::lsys::{ActualParam, Element, LSystem, Production, Symbol};
+use std::collections::BTreeMap;
+
+/// Closed-form analysis of a deterministic context-free (D0L) lsystem, answering
+/// questions about generation `n` without expanding the string.
+///
+/// Each symbol is rewritten by a fixed successor, so the symbol counts of a
+/// generation are the axiom's counts multiplied by the `n`th power of the growth
+/// matrix, where row `a` holds the number of each symbol in the successor of `a`.
+/// Counts which do not fit in a `u64` saturate at `u64::MAX`.
+#[derive(Debug)]
+pub struct Analysis<'a> {
+    lsystem: &'a LSystem,
+    axiom: Vec<Element<ActualParam>>,
+    symbols: Vec<Symbol>,
+    /// The production for each symbol, by index into `symbols`
+    productions: Vec<Option<&'a Production>>,
+    growth: Vec<Vec<u64>>,
+}
+
+impl<'a> Analysis<'a> {
+    /// Fails unless every symbol has at most one production, which is unconditional,
+    /// certain and matches every occurrence of the symbol.
+    pub fn new(lsystem: &'a LSystem) -> Result<Self> {
+        let not_d0l = |symbol: Symbol, reason: &str| {
+            Error::Unsupported(format!(
+                "closed-form analysis needs a deterministic lsystem, but the productions for '{}' {}",
+                symbol, reason
+            ))
+        };
+        let mut by_symbol: BTreeMap<Symbol, &Production> = BTreeMap::new();
+        for production in lsystem.productions() {
+            let symbol = production.predecessor().symbol;
+            if production.condition().is_some() {
+                return Err(not_d0l(symbol, "have a condition"));
+            }
+            if production.probability().constant() != Some(1.0) {
+                return Err(not_d0l(symbol, "are stochastic"));
+            }
+            if by_symbol.insert(symbol, production).is_some() {
+                return Err(not_d0l(symbol, "are not unique"));
+            }
+        }
+
+        let axiom: Vec<_> = lsystem.axiom().into_iter().cloned().collect();
+        let mut arities: BTreeMap<Symbol, usize> = BTreeMap::new();
+        let occurrences = axiom
+            .iter()
+            .map(|element| (element.symbol, element.params.len()))
+            .chain(by_symbol.values().flat_map(|production| {
+                production
+                    .successors()
+                    .iter()
+                    .map(|element| (element.symbol, element.params.len()))
+            }));
+        for (symbol, arity) in occurrences {
+            if let Some(production) = by_symbol.get(&symbol) {
+                if production.predecessor().params.len() != arity {
+                    return Err(not_d0l(symbol, "do not match every occurrence"));
+                }
+            }
+            arities.insert(symbol, arity);
+        }
+        for symbol in by_symbol.keys() {
+            arities.entry(*symbol).or_insert(0);
+        }
+
+        let symbols: Vec<Symbol> = arities.keys().cloned().collect();
+        let index = |symbol: Symbol| symbols.binary_search(&symbol).unwrap();
+        let productions: Vec<_> = symbols
+            .iter()
+            .map(|symbol| by_symbol.get(symbol).cloned())
+            .collect();
+        let mut growth = vec![vec![0; symbols.len()]; symbols.len()];
+        for (i, production) in productions.iter().enumerate() {
+            match production {
+                Some(production) => {
+                    for element in production.successors() {
+                        growth[i][index(element.symbol)] += 1;
+                    }
+                }
+                None => growth[i][i] = 1,
+            }
+        }
+        Ok(Analysis {
+            lsystem,
+            axiom,
+            symbols,
+            productions,
+            growth,
+        })
+    }
+
+    /// The symbols of the lsystem, in the order of the rows and columns of the growth matrix
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    pub fn growth_matrix(&self) -> &[Vec<u64>] {
+        &self.growth
+    }
+
+    /// The number of elements of each symbol in generation `n`
+    pub fn counts(&self, n: usize) -> BTreeMap<Symbol, u64> {
+        let mut counts = vec![0u64; self.symbols.len()];
+        for element in &self.axiom {
+            counts[self.index(element.symbol)] += 1;
+        }
+        let power = power(&self.growth, n);
+        self.symbols
+            .iter()
+            .enumerate()
+            .map(|(j, symbol)| {
+                let count = counts.iter().enumerate().fold(0u64, |sum, (i, count)| {
+                    sum.saturating_add(count.saturating_mul(power[i][j]))
+                });
+                (*symbol, count)
+            })
+            .collect()
+    }
+
+    /// The length of generation `n`
+    pub fn length(&self, n: usize) -> u64 {
+        self.counts(n)
+            .values()
+            .fold(0, |sum, count| sum.saturating_add(*count))
+    }
+
+    /// Finds element `k` (from 0) of generation `n` by descending the derivation
+    /// tree from the axiom, expanding only the branch which holds it.
+    pub fn module(&self, n: usize, k: usize) -> Result<Option<Element<ActualParam>>> {
+        let lengths = self.lengths(n);
+        let mut k = k as u64;
+        // The number of elements of each generation before the branch being descended,
+        // which gives the `index` of each element on the way down
+        let mut before = vec![0u64; n + 1];
+        let mut elements = self.axiom.clone();
+        for depth in (0..=n).rev() {
+            let generation = n - depth;
+            let mut found = None;
+            for element in elements {
+                let symbol = self.index(element.symbol);
+                let length = lengths[depth][symbol];
+                if k < length {
+                    found = Some(element);
+                    break;
+                }
+                k -= length;
+                for (h, count) in before.iter_mut().enumerate().skip(generation) {
+                    *count = count.saturating_add(lengths[h - generation][symbol]);
+                }
+            }
+            let element = match found {
+                Some(element) => element,
+                None => return Ok(None),
+            };
+            if depth == 0 {
+                return Ok(Some(element));
+            }
+            let globals = self
+                .lsystem
+                .globals(generation, before[generation] as usize);
+            elements = match self.productions[self.index(element.symbol)] {
+                Some(production) => production
+                    .apply(&element, &globals)?
+                    .into_iter()
+                    .cloned()
+                    .collect(),
+                None => vec![element],
+            };
+        }
+        unreachable!()
+    }
+
+    /// The length each symbol grows to after each number of steps, up to `n`
+    fn lengths(&self, n: usize) -> Vec<Vec<u64>> {
+        let mut lengths = vec![vec![1; self.symbols.len()]];
+        for depth in 1..=n {
+            let previous = &lengths[depth - 1];
+            let next = self
+                .growth
+                .iter()
+                .map(|row| {
+                    row.iter().zip(previous).fold(0u64, |sum, (count, length)| {
+                        sum.saturating_add(count.saturating_mul(*length))
+                    })
+                })
+                .collect();
+            lengths.push(next);
+        }
+        lengths
+    }
+
+    fn index(&self, symbol: Symbol) -> usize {
+        self.symbols.binary_search(&symbol).unwrap()
+    }
+}
+
+fn multiply(a: &[Vec<u64>], b: &[Vec<u64>]) -> Vec<Vec<u64>> {
+    a.iter()
+        .map(|row| {
+            (0..b.len())
+                .map(|j| {
+                    row.iter().zip(b).fold(0u64, |sum, (x, b_row)| {
+                        sum.saturating_add(x.saturating_mul(b_row[j]))
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Raises a square matrix to the power `n` by repeated squaring
+fn power(matrix: &[Vec<u64>], mut n: usize) -> Vec<Vec<u64>> {
+    let size = matrix.len();
+    let mut result: Vec<Vec<u64>> = (0..size)
+        .map(|i| (0..size).map(|j| if i == j { 1 } else { 0 }).collect())
+        .collect();
+    let mut square = matrix.to_vec();
+    while n > 0 {
+        if n & 1 == 1 {
+            result = multiply(&result, &square);
+        }
+        square = multiply(&square, &square);
+        n >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_lsys;
+    use crate::stats::GenerationStats;
+
+    #[test]
+    fn counts_match_expansion() {
+        let lsystem = parse_lsys("F-F\nF=F+F-F\n+=\n").unwrap();
+        let analysis = Analysis::new(&lsystem).unwrap();
+        let counts = analysis.counts(5);
+        let stats = GenerationStats::new(5, &lsystem.generation(5).unwrap(), Vec::new(), false);
+        assert_eq!(analysis.length(5), stats.length as u64);
+        for (symbol, count) in &stats.symbols {
+            assert_eq!(counts[symbol], *count as u64);
+        }
+    }
+
+    #[test]
+    fn module_matches_expansion() {
+        let lsystem = parse_lsys("A(1)B\nA(x)=A(x+1)B\nB=A(index+gen)").unwrap();
+        let analysis = Analysis::new(&lsystem).unwrap();
+        let expanded: Vec<String> = lsystem
+            .generation(6)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.to_string())
+            .collect();
+        for (k, element) in expanded.iter().enumerate() {
+            let module = analysis.module(6, k).unwrap().unwrap();
+            assert_eq!(&module.to_string(), element);
+        }
+        assert!(analysis.module(6, expanded.len()).unwrap().is_none());
+    }
+
+    #[test]
+    fn saturate_huge_generations() {
+        let lsystem = parse_lsys("F\nF=FF").unwrap();
+        let analysis = Analysis::new(&lsystem).unwrap();
+        assert_eq!(analysis.length(40), 1 << 40);
+        assert_eq!(analysis.length(100), u64::MAX);
+        assert_eq!(analysis.module(100, 12345).unwrap().unwrap().symbol, 'F');
+    }
+
+    #[test]
+    fn reject_stochastic_productions() {
+        let lsystem = parse_lsys("F\n{0.5} F=F[+F]\n{0.5} F=FF").unwrap();
+        match Analysis::new(&lsystem) {
+            Err(Error::Unsupported(_)) => (),
+            other => panic!("expected analysis error, got {:?}", other),
+        }
+    }
+}
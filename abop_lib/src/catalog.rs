@@ -0,0 +1,198 @@
+use crate::error::Result;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The lsystem files found in a directory, sorted by name
+#[derive(Debug)]
+pub struct Catalog {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub name: String,
+    pub path: PathBuf,
+    /// The first comment line of the file
+    pub description: Option<String>,
+}
+
+impl Catalog {
+    /// Lists the files in `dir` which have no extension or the extension `lsys`.
+    /// Files which cannot be read as text, such as binaries, are left out.
+    pub fn read_dir(dir: &Path) -> Result<Self> {
+        let mut entries = Vec::new();
+        for dir_entry in fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+            let is_lsys = match path.extension() {
+                None => true,
+                Some(extension) => extension == "lsys",
+            };
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if !name.starts_with('.') => name.to_string(),
+                _ => continue,
+            };
+            if is_lsys && path.is_file() {
+                let text = match fs::read_to_string(&path) {
+                    Ok(text) => text,
+                    Err(_) => continue,
+                };
+                let description = describe(&text);
+                entries.push(Entry {
+                    name,
+                    path,
+                    description,
+                });
+            }
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Catalog { entries })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Finds the index of an entry from its number in the listing (starting at 1)
+    /// or from a fuzzy match on its name. The letters of the query must appear in
+    /// order in the name; exact, prefix and substring matches are preferred, then
+    /// shorter names.
+    pub fn find(&self, query: &str) -> Option<usize> {
+        let query = query.trim();
+        if let Ok(n) = query.parse::<usize>() {
+            return if n >= 1 && n <= self.len() {
+                Some(n - 1)
+            } else {
+                None
+            };
+        }
+        let query = query.to_lowercase();
+        if query.is_empty() {
+            return None;
+        }
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entry)| {
+                let name = entry.name.to_lowercase();
+                let rank = if name == query {
+                    0
+                } else if name.starts_with(&query) {
+                    1
+                } else if name.contains(&query) {
+                    2
+                } else if is_subsequence(&query, &name) {
+                    3
+                } else {
+                    return None;
+                };
+                Some(((rank, name.len()), i))
+            })
+            .min()
+            .map(|(_, i)| i)
+    }
+}
+
+impl fmt::Display for Catalog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, entry) in self.entries.iter().enumerate() {
+            writeln!(f, "{:>3}  {}", i + 1, entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.description {
+            Some(description) => write!(f, "{:<16} {}", self.name, description),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+fn describe(source: &str) -> Option<String> {
+    source
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("//") || line.starts_with("/*"))
+        .map(|line| {
+            line.trim_start_matches('/')
+                .trim_start_matches('*')
+                .trim_end_matches("*/")
+                .trim()
+                .to_string()
+        })
+        .filter(|description| !description.is_empty())
+}
+
+fn is_subsequence(query: &str, name: &str) -> bool {
+    let mut chars = name.chars();
+    query.chars().all(|c| chars.any(|x| x == c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog(names: &[&str]) -> Catalog {
+        Catalog {
+            entries: names
+                .iter()
+                .map(|name| Entry {
+                    name: name.to_string(),
+                    path: PathBuf::from(name),
+                    description: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn skip_binary_files() {
+        let dir = std::env::temp_dir().join("abop_test_catalog");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("plant"), "// a plant\nF\n").unwrap();
+        fs::write(dir.join("binary"), [0xff, 0xfe, 0x00, 0x80]).unwrap();
+        let catalog = Catalog::read_dir(&dir).unwrap();
+        assert_eq!(catalog.len(), 1);
+        assert_eq!(catalog.entries()[0].name, "plant");
+    }
+
+    #[test]
+    fn find_by_number() {
+        let catalog = catalog(&["koch", "plant", "tree"]);
+        assert_eq!(catalog.find("2"), Some(1));
+        assert_eq!(catalog.find("4"), None);
+        assert_eq!(catalog.find("0"), None);
+    }
+
+    #[test]
+    fn find_fuzzy() {
+        let catalog = catalog(&["koch", "plant", "tree", "tree2", "testcond"]);
+        assert_eq!(catalog.find("tree"), Some(2));
+        assert_eq!(catalog.find("tr"), Some(2));
+        assert_eq!(catalog.find("ee2"), Some(3));
+        assert_eq!(catalog.find("tcd"), Some(4));
+        assert_eq!(catalog.find("PLA"), Some(1));
+        assert_eq!(catalog.find("xyz"), None);
+    }
+
+    #[test]
+    fn describe_first_comment() {
+        assert_eq!(
+            describe("#set a=1\n// this is amazing\nF\n// not this"),
+            Some("this is amazing".to_string())
+        );
+        assert_eq!(describe("/* block */\nF"), Some("block".to_string()));
+        assert_eq!(describe("F\nF=FF"), None);
+    }
+}
@@ -1,4 +1,9 @@
+use crate::error::{Error, Result};
+use crate::limits::Limits;
+use config::ConfigError;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -12,6 +17,19 @@ pub struct Opt {
     )]
     pub iterations: usize,
 
+    /// Fail when a generation has more than this number of elements.
+    #[structopt(name = "max length", long = "max-length")]
+    pub max_length: Option<usize>,
+
+    /// Fail when a generation needs more than about this many MiB of memory, or
+    /// never if 0. [default: 1024]
+    #[structopt(name = "max memory", long = "max-memory")]
+    pub max_memory: Option<usize>,
+
+    /// Fail when deriving a generation takes longer than this number of seconds.
+    #[structopt(name = "max time", long = "max-time")]
+    pub max_time: Option<f32>,
+
     /// File from which to read the lsystem.
     /// If a filename is not supplied input will be taken from STDIN.
     #[structopt(name = "FILE", parse(from_os_str))]
@@ -21,26 +39,74 @@ pub struct Opt {
     #[structopt(name = "directory", short, long, parse(from_os_str))]
     pub dir: Option<PathBuf>,
 
-    /// Run in an interactive loop, prompting for generation of the next iteration.
+    /// Run in an interactive loop, reading commands from STDIN, one per line. Type 'help'
+    /// for the list of commands. The lsystem must then be read from FILE or '--dir'.
     #[structopt(short, long)]
     pub interactive: bool,
 
+    /// Output the derivation of a module of the last generation, given by its index
+    /// (from 0): the module it came from in each generation since the axiom and the
+    /// production which rewrote it.
+    #[structopt(
+        name = "module",
+        long = "trace",
+        conflicts_with = "model, lsystem, graphics, stats, stream"
+    )]
+    pub trace: Option<usize>,
+
+    /// Leave an element unchanged when the random draw falls past the total probability
+    /// of its stochastic productions, as earlier versions did, instead of using the
+    /// probabilities as weights.
+    #[structopt(long = "legacy-probabilities")]
+    pub legacy_probabilities: bool,
+
+    /// Derive the last generation depth-first, writing or drawing it element by element
+    /// without storing any generation whole. Only for lsystems without stochastic
+    /// productions.
+    #[structopt(long, conflicts_with = "interactive, stats, lstring, graphics")]
+    pub stream: bool,
+
+    /// Draw thin branches with fewer sides, as thin as needed for the model to have at
+    /// most this many triangles. Branches are never drawn with fewer than three sides.
+    #[structopt(name = "max triangles", long = "max-triangles")]
+    pub max_triangles: Option<usize>,
+
     /// Produce verbose output. Currently this option only has an effect in
     /// combination with '-l'.
     #[structopt(short, long)]
     pub verbose: bool,
 
-    /// Output the generated model without rendering graphics
+    /// Output the generated model without rendering graphics. The model is written as
+    /// Wavefront OBJ (vertices with colours, normals and triangular faces), which can be
+    /// read back with '-g'.
     #[structopt(name = "model", short, long, conflicts_with = "lsystem, graphics")]
     pub output_model: bool,
 
     /// Output the generated lsystem without interpreting as turtle commands
     /// or rendering graphics. By default, prints the current lsystem string only. To show the
-    /// rules, use the '-v' option.
+    /// rules, use the '-v' option. The lsystem string can be read back with '--from-lstring'.
     #[structopt(name = "lsystem", short, long, conflicts_with = "model, graphics")]
     pub output_lsystem: bool,
 
-    /// Input a model and render it as graphics. (Skip the lsystem generation and turtle interpretation stages.)
+    /// Output statistics of every generation up to the last iteration, as a 'table' or
+    /// 'json' (one object per line): the string length, whether a fixed point was
+    /// reached, how many elements each production rewrote and the count of each symbol.
+    /// Iteration stops early at a fixed point.
+    #[structopt(
+        name = "stats",
+        long,
+        raw(possible_values = r#"&["table", "json"]"#),
+        conflicts_with = "model, lsystem, graphics, interactive"
+    )]
+    pub stats: Option<StatsFormat>,
+
+    /// Input an lsystem string, as written by '-l', and interpret it as turtle commands.
+    /// (Skip the lsystem parsing and generation stages.)
+    #[structopt(name = "lstring", long = "from-lstring", conflicts_with = "graphics")]
+    pub input_lstring: bool,
+
+    /// Input a model, as written by '-m', and render it as graphics. (Skip the lsystem
+    /// generation and turtle interpretation stages.)
     #[structopt(
         name = "graphics",
         short,
@@ -52,14 +118,176 @@ pub struct Opt {
 
 impl Opt {
     pub fn use_graphics(&self) -> bool {
-        !(self.output_model || self.output_lsystem)
+        !(self.output_model || self.output_lsystem || self.stats.is_some() || self.trace.is_some())
+    }
+
+    /// The resource limits for each generation given by the options. An infinite
+    /// time means no limit.
+    pub fn limits(&self) -> Result<Limits> {
+        let default = Limits::default();
+        let max_memory = match self.max_memory {
+            Some(0) => None,
+            Some(mib) => Some(
+                mib.checked_mul(1 << 20)
+                    .ok_or_else(|| invalid(format!("--max-memory {} is too large", mib)))?,
+            ),
+            None => default.max_memory,
+        };
+        let max_time = match self.max_time {
+            Some(seconds) if seconds == f32::INFINITY => None,
+            Some(seconds) => Some(
+                Duration::try_from_secs_f32(seconds.max(0.0))
+                    .map_err(|_| invalid(format!("--max-time {} is too large", seconds)))?,
+            ),
+            None => None,
+        };
+        Ok(Limits {
+            max_length: self.max_length,
+            max_memory,
+            max_time,
+        })
     }
 
     pub fn use_turtle(&self) -> bool {
-        !self.output_lsystem && !self.input_graphics_model
+        !self.output_lsystem && self.stats.is_none() && !self.input_graphics_model
     }
 
     pub fn use_lsystem(&self) -> bool {
         !self.input_graphics_model
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatsFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for StatsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "table" => Ok(StatsFormat::Table),
+            "json" => Ok(StatsFormat::Json),
+            _ => Err(format!("unknown statistics format '{}'", s)),
+        }
+    }
+}
+
+/// A command of the interactive loop
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Next,
+    Previous,
+    Goto(usize),
+    Rules,
+    /// Show the derivation of the module at an index of the current generation
+    Trace(usize),
+    /// Predict the size of a generation of a deterministic lsystem without deriving it
+    Predict(usize),
+    /// A setting written as `key=value`, as for `#set`
+    Set(String),
+    /// A new seed for stochastic productions, or `None` to choose one at random
+    Reseed(Option<u64>),
+    Help,
+    Quit,
+}
+
+impl Command {
+    pub const HELP: &'static str = "\
+n, next           generate the next generation (also an empty line)
+p, prev           go back to the previous generation
+g, goto N         go to generation N (also just N)
+r, rules          show the productions
+trace K           show how module K of the current generation was derived
+predict N         show the length and symbol counts of generation N without deriving it
+s, set KEY=VALUE  change a setting, as with #set
+reseed [SEED]     choose new random values for stochastic productions
+h, help           show this help
+q, quit           quit";
+}
+
+impl FromStr for Command {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (name, arg) = match s.find(char::is_whitespace) {
+            Some(i) => (&s[..i], s[i..].trim()),
+            None => (s, ""),
+        };
+        let unknown = || Error::Command(s.to_string());
+        match (name, arg) {
+            ("", _) | ("n", "") | ("next", "") => Ok(Command::Next),
+            ("p", "") | ("prev", "") | ("previous", "") => Ok(Command::Previous),
+            ("g", n) | ("goto", n) => n.parse().map(Command::Goto).map_err(|_| unknown()),
+            ("r", "") | ("rules", "") => Ok(Command::Rules),
+            ("t", k) | ("trace", k) => k.parse().map(Command::Trace).map_err(|_| unknown()),
+            ("predict", n) => n.parse().map(Command::Predict).map_err(|_| unknown()),
+            ("s", setting) | ("set", setting) if !setting.is_empty() => {
+                Ok(Command::Set(setting.to_string()))
+            }
+            ("reseed", "") => Ok(Command::Reseed(None)),
+            ("reseed", seed) => seed
+                .parse()
+                .map(|seed| Command::Reseed(Some(seed)))
+                .map_err(|_| unknown()),
+            ("h", "") | ("help", "") | ("?", "") => Ok(Command::Help),
+            ("q", "") | ("quit", "") | ("exit", "") => Ok(Command::Quit),
+            (n, "") => n.parse().map(Command::Goto).map_err(|_| unknown()),
+            _ => Err(unknown()),
+        }
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::Config(ConfigError::Message(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!("".parse::<Command>().unwrap(), Command::Next);
+        assert_eq!(" next ".parse::<Command>().unwrap(), Command::Next);
+        assert_eq!("p".parse::<Command>().unwrap(), Command::Previous);
+        assert_eq!("goto 12".parse::<Command>().unwrap(), Command::Goto(12));
+        assert_eq!("7".parse::<Command>().unwrap(), Command::Goto(7));
+        assert_eq!("trace 3".parse::<Command>().unwrap(), Command::Trace(3));
+        assert_eq!(
+            "predict 20".parse::<Command>().unwrap(),
+            Command::Predict(20)
+        );
+        assert_eq!(
+            "set turtle.default.angle=30".parse::<Command>().unwrap(),
+            Command::Set("turtle.default.angle=30".to_string())
+        );
+        assert_eq!("reseed".parse::<Command>().unwrap(), Command::Reseed(None));
+        assert_eq!(
+            "reseed 42".parse::<Command>().unwrap(),
+            Command::Reseed(Some(42))
+        );
+        assert_eq!("q".parse::<Command>().unwrap(), Command::Quit);
+    }
+
+    #[test]
+    fn limits_out_of_range() {
+        let opt = Opt::from_iter(&["abop", "--max-time", "inf"]);
+        assert_eq!(opt.limits().unwrap().max_time, None);
+        let opt = Opt::from_iter(&["abop", "--max-time", "1e30"]);
+        assert!(opt.limits().is_err());
+        let opt = Opt::from_iter(&["abop", "--max-memory", &usize::MAX.to_string()]);
+        assert!(opt.limits().is_err());
+    }
+
+    #[test]
+    fn parse_unknown_commands() {
+        assert!("jump".parse::<Command>().is_err());
+        assert!("goto x".parse::<Command>().is_err());
+        assert!("set".parse::<Command>().is_err());
+        assert!("n 3".parse::<Command>().is_err());
+    }
+}
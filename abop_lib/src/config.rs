@@ -2,35 +2,85 @@ extern crate config;
 
 use config::Value;
 
-use config::Config;
-use serde::de::Deserialize;
-use std::sync::RwLock;
+use crate::error::{Error, Result};
+use config::{Config, ConfigError};
+use serde::de::DeserializeOwned;
+use std::sync::{PoisonError, RwLock, RwLockWriteGuard};
 
 lazy_static! {
-    pub static ref SETTINGS: RwLock<Config> = {
-        let mut settings = Config::default();
-        settings
-            .merge(config::File::with_name("Settings"))
-            .unwrap()
-            .merge(config::Environment::with_prefix("LSYS"))
-            .unwrap();
-        RwLock::new(settings)
+    static ref SETTINGS: RwLock<Option<Config>> = RwLock::new(None);
+}
+
+fn load_settings() -> Result<Config> {
+    let mut settings = Config::default();
+    settings
+        .merge(config::File::with_name("Settings"))?
+        .merge(config::Environment::with_prefix("LSYS"))?;
+    Ok(settings)
+}
+
+/// Each setting is only ever read or written whole, so the settings are still sound if
+/// a thread panicked while holding the lock
+fn lock_settings() -> RwLockWriteGuard<'static, Option<Config>> {
+    SETTINGS.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn with_settings<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce(&mut Config) -> std::result::Result<T, ConfigError>,
+{
+    let mut guard = lock_settings();
+    let settings = match &mut *guard {
+        Some(settings) => settings,
+        empty => empty.get_or_insert(load_settings()?),
     };
+    f(settings).map_err(Error::from)
+}
+
+pub fn get_config<T>(name: &str) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    with_settings(|settings| settings.get::<T>(name))
 }
 
-pub fn get_config<T>(name: &'static str) -> T
+/// The setting `name`, or `None` if it is not set. A setting which is set but cannot
+/// be read as a `T` is still an error.
+pub fn find_config<T>(name: &str) -> Result<Option<T>>
 where
-    T: Deserialize<'static>,
+    T: DeserializeOwned,
 {
-    SETTINGS.read().unwrap().get::<T>(name).unwrap()
+    match get_config(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::Config(ConfigError::NotFound(_))) => Ok(None),
+        Err(e) => Err(e),
+    }
 }
 
-pub fn set_config<T>(name: &str, value: T)
+pub fn set_config<T>(name: &str, value: T) -> Result<()>
 where
     T: Into<Value>,
 {
-    match SETTINGS.write().unwrap().set(name, value) {
-        Ok(_) => (),
-        Err(e) => panic!("couldn't write config: {}", e),
+    with_settings(|settings| settings.set(name, value).map(|_| ()))
+}
+
+/// Discards settings made with `set_config`, so that the next read starts again
+/// from the settings file and environment.
+pub fn reset_config() -> Result<()> {
+    *lock_settings() = None;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_missing_or_mistyped() {
+        set_config("test.find.number", "not a number").unwrap();
+        assert!(find_config::<f32>("test.find.number").is_err());
+        assert_eq!(find_config::<f32>("test.find.missing").unwrap(), None);
+        set_config("test.find.number", 2.5).unwrap();
+        assert_eq!(find_config::<f32>("test.find.number").unwrap(), Some(2.5));
     }
 }
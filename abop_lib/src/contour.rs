@@ -0,0 +1,235 @@
+//! Cross-sections of branches, chosen per branch with the turtle command `@#(i)`.
+//!
+//! Contour 0 is a circle, and contour `i` is the `i`-th name listed in the setting
+//! `turtle.contours`. A contour `name` is defined by either of
+//!
+//! ```text
+//! contour.name.points=[[x, y], ...]
+//! contour.name.file="name.con"
+//! ```
+//!
+//! where a contour file has one `x y` point per line and `#` starts a comment. The
+//! points go round the cross-section with x to the turtle's right and y up, and the
+//! contour is closed from the last point back to the first. Contours are resampled to
+//! `turtle.default.shape.segments` points evenly spaced along their outline, so that
+//! branches can change from one contour to another.
+extern crate nalgebra_glm as glm;
+use crate::config::find_config;
+use crate::error::{Error, Result};
+use config::ConfigError;
+use glm::Vec2;
+use std::fs;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Contour {
+    points: Vec<Vec2>,
+}
+
+impl Contour {
+    /// The contour through `points`, which are put clockwise, as the circle's are,
+    /// so that the faces of tubes point out
+    pub fn new(points: Vec<Vec2>) -> Result<Self> {
+        let mut contour = Contour { points };
+        if contour.points.len() < 3 || contour.perimeter() <= 0.0 {
+            return Err(Error::Config(ConfigError::Message(
+                "a contour needs at least three distinct points".to_string(),
+            )));
+        }
+        if contour.area() > 0.0 {
+            contour.points.reverse();
+        }
+        Ok(contour)
+    }
+
+    /// A unit circle of `n` points
+    pub fn circle(n: usize) -> Self {
+        let points = (0..n)
+            .map(|i| {
+                let angle = 2.0 * std::f32::consts::PI * i as f32 / n as f32;
+                Vec2::new(angle.cos(), -angle.sin())
+            })
+            .collect();
+        Contour { points }
+    }
+
+    /// The contour defined by the `contour.<name>` settings
+    pub fn named(name: &str) -> Result<Self> {
+        let key = |property: &str| format!("contour.{}.{}", name, property);
+        if let Some(points) = find_config::<Vec<[f32; 2]>>(&key("points"))? {
+            Contour::new(points.into_iter().map(Vec2::from).collect())
+        } else if let Some(path) = find_config::<String>(&key("file"))? {
+            fs::read_to_string(&path)?.parse()
+        } else {
+            Err(Error::Config(ConfigError::Message(format!(
+                "contour '{}' is not defined, set '{}' or '{}'",
+                name,
+                key("points"),
+                key("file")
+            ))))
+        }
+    }
+
+    /// The contours of `turtle.contours`, after the circle of `n` points, all
+    /// resampled to `n` points
+    pub fn from_config(n: usize) -> Result<Vec<Self>> {
+        let names: Vec<String> = find_config("turtle.contours")?.unwrap_or_default();
+        let mut contours = vec![Contour::circle(n)];
+        for name in names {
+            contours.push(Contour::named(&name)?.resample(n));
+        }
+        Ok(contours)
+    }
+
+    pub fn points(&self) -> &[Vec2] {
+        &self.points
+    }
+
+    /// The outward normal at each point, between the normals of the edges either side
+    pub fn normals(&self) -> Vec<Vec2> {
+        let n = self.points.len();
+        let edge_normal = |i: usize| {
+            let d = self.points[(i + 1) % n] - self.points[i];
+            let normal = Vec2::new(-d.y, d.x);
+            if glm::length(&normal) > 0.0 {
+                glm::normalize(&normal)
+            } else {
+                normal
+            }
+        };
+        (0..n)
+            .map(|i| {
+                let normal = edge_normal((i + n - 1) % n) + edge_normal(i);
+                if glm::length(&normal) > 1e-6 {
+                    glm::normalize(&normal)
+                } else {
+                    edge_normal(i)
+                }
+            })
+            .collect()
+    }
+
+    /// `n` points evenly spaced along the outline, starting from the first point
+    pub fn resample(&self, n: usize) -> Self {
+        let len = self.points.len();
+        let step = self.perimeter() / n as f32;
+        let mut points = Vec::with_capacity(n);
+        let mut edge = 0;
+        let mut edge_start = 0.0;
+        for i in 0..n {
+            let distance = i as f32 * step;
+            let mut edge_length = glm::distance(&self.points[edge], &self.points[(edge + 1) % len]);
+            while edge_start + edge_length < distance && edge + 1 < len {
+                edge_start += edge_length;
+                edge += 1;
+                edge_length = glm::distance(&self.points[edge], &self.points[(edge + 1) % len]);
+            }
+            let t = if edge_length > 0.0 {
+                (distance - edge_start) / edge_length
+            } else {
+                0.0
+            };
+            points.push(glm::lerp(
+                &self.points[edge],
+                &self.points[(edge + 1) % len],
+                t.min(1.0),
+            ));
+        }
+        Contour { points }
+    }
+
+    fn perimeter(&self) -> f32 {
+        let n = self.points.len();
+        (0..n)
+            .map(|i| glm::distance(&self.points[i], &self.points[(i + 1) % n]))
+            .sum()
+    }
+
+    /// Twice the signed area, positive when the points go counterclockwise
+    fn area(&self) -> f32 {
+        let n = self.points.len();
+        (0..n)
+            .map(|i| {
+                let (p, q) = (self.points[i], self.points[(i + 1) % n]);
+                p.x * q.y - q.x * p.y
+            })
+            .sum()
+    }
+}
+
+impl FromStr for Contour {
+    type Err = Error;
+
+    /// Reads a contour, one `x y` point per line
+    fn from_str(s: &str) -> Result<Self> {
+        let mut points = Vec::new();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let values = line
+                .split_whitespace()
+                .map(|word| {
+                    word.parse::<f32>().map_err(|e| Error::Token {
+                        token: format!("{} (line {})", word, n + 1),
+                        source: Box::new(e),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            match values[..] {
+                [] => (),
+                [x, y] => points.push(Vec2::new(x, y)),
+                _ => {
+                    return Err(Error::Format {
+                        line: n + 1,
+                        message: "expected a point 'x y'".to_string(),
+                    })
+                }
+            }
+        }
+        Contour::new(points)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_setting;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn square_contour() {
+        let square: Contour = "# a square\n1 1\n1 -1\n-1 -1\n-1 1\n".parse().unwrap();
+        let normals = square.normals();
+        let diagonal = 0.5f32.sqrt();
+        assert_relative_eq!(normals[0], Vec2::new(diagonal, diagonal), epsilon = 1e-6);
+        assert_relative_eq!(normals[2], Vec2::new(-diagonal, -diagonal), epsilon = 1e-6);
+
+        let resampled = square.resample(8);
+        assert_eq!(resampled.points().len(), 8);
+        assert_relative_eq!(resampled.points()[1], Vec2::new(1.0, 0.0), epsilon = 1e-6);
+        assert_relative_eq!(resampled.points()[5], Vec2::new(-1.0, 0.0), epsilon = 1e-6);
+        // Given either way round, the contour goes clockwise
+        let reversed = Contour::new(square.points().iter().rev().cloned().collect()).unwrap();
+        assert_eq!(reversed.points()[0], Vec2::new(1.0, 1.0));
+        assert_relative_eq!(
+            reversed.normals()[1],
+            Vec2::new(diagonal, -diagonal),
+            epsilon = 1e-6
+        );
+        assert!("0 0\n1 1\n".parse::<Contour>().is_err());
+        match Contour::named("nosuchcontour") {
+            Err(Error::Config(_)) => (),
+            other => panic!("expected configuration error, got {:?}", other),
+        }
+        parse_setting("contour.mistyped.points=\"square\"").unwrap();
+        let error = Contour::named("mistyped").unwrap_err().to_string();
+        assert!(!error.contains("not defined"), "{}", error);
+    }
+
+    #[test]
+    fn circle_normals_are_radial() {
+        let circle = Contour::circle(12);
+        for (p, n) in circle.points().iter().zip(circle.normals()) {
+            assert_relative_eq!(*p, n, epsilon = 1e-5);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use crate::limits::Limit;
+use crate::{expr, parser};
+use config::ConfigError;
+use std::error;
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong while parsing, generating or interpreting an lsystem
+#[derive(Debug)]
+pub enum Error {
+    /// The lsystem source does not match the lsystem grammar
+    Syntax(Box<pest::error::Error<parser::Rule>>),
+    /// An expression does not match the expression grammar
+    Expression(Box<pest::error::Error<expr::Rule>>),
+    /// A token accepted by the grammar could not be converted to a value
+    Token {
+        token: String,
+        source: Box<dyn error::Error + Send + Sync>,
+    },
+    /// An expression refers to a variable which is not bound in its context
+    UndefinedVariable(char),
+    /// A line of a mesh or other text input is malformed
+    Format {
+        line: usize,
+        message: String,
+    },
+    /// Deriving a generation went past one of the configured limits
+    LimitExceeded {
+        generation: usize,
+        limit: Limit,
+    },
+    /// The lsystem lacks a property which an operation needs, such as being deterministic
+    Unsupported(String),
+    /// An interactive command was not understood
+    Command(String),
+    /// Settings could not be read or written
+    Config(ConfigError),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Syntax(e) => write!(f, "invalid lsystem:\n{}", e),
+            Error::Expression(e) => write!(f, "invalid expression:\n{}", e),
+            Error::Token { token, source } => write!(f, "invalid token '{}': {}", token, source),
+            Error::UndefinedVariable(var) => write!(f, "undefined variable '{}'", var),
+            Error::Format { line, message } => write!(f, "line {}: {}", line, message),
+            Error::LimitExceeded { generation, limit } => {
+                write!(f, "generation {} exceeds {}", generation, limit)
+            }
+            Error::Unsupported(message) => write!(f, "{}", message),
+            Error::Command(command) => write!(f, "unknown command '{}', try 'help'", command),
+            Error::Config(e) => write!(f, "configuration error: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Syntax(e) => Some(e.as_ref()),
+            Error::Expression(e) => Some(e.as_ref()),
+            Error::Token { source, .. } => Some(source.as_ref()),
+            Error::UndefinedVariable(_)
+            | Error::Format { .. }
+            | Error::LimitExceeded { .. }
+            | Error::Unsupported(_)
+            | Error::Command(_) => None,
+            Error::Config(e) => Some(e),
+            Error::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<pest::error::Error<parser::Rule>> for Error {
+    fn from(e: pest::error::Error<parser::Rule>) -> Self {
+        Error::Syntax(Box::new(e))
+    }
+}
+
+impl From<pest::error::Error<expr::Rule>> for Error {
+    fn from(e: pest::error::Error<expr::Rule>) -> Self {
+        Error::Expression(Box::new(e))
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        Error::Config(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -1,4 +1,4 @@
-use pest::error::Error;
+use crate::error::{Error, Result};
 use pest::iterators::{Pair, Pairs};
 use pest::prec_climber::*;
 use pest::Parser;
@@ -9,14 +9,14 @@ use std::str::FromStr;
 /// Represents an simple mathematical expression
 /// which can be evaluated in a context in order to obtain a result
 /// ```
-/// use abop::expr::{Expression, Context};
+/// use abop_lib::expr::{Expression, Context};
 /// let exp : Expression = "1".parse().ok().unwrap();
 /// let mut context: Context = Default::default();
-/// assert_eq!(exp.eval(&context), 1.0);
+/// assert_eq!(exp.eval(&context).unwrap(), 1.0);
 /// context.push(('x', 4.0));
 /// context.push(('y', 6.0));
 /// let exp : Expression = "(x^2+6*2)*(10-y)".parse().ok().unwrap();
-/// assert_eq!(exp.eval(&context), 112.0);
+/// assert_eq!(exp.eval(&context).unwrap(), 112.0);
 /// ```
 #[derive(Debug, Clone)]
 pub enum Expression {
@@ -41,33 +41,60 @@ type Value = f32;
 
 pub type Context = Vec<(Var, Value)>;
 
+/// The variables bound by the lsystem for every element, written `gen`, `time` and
+/// `index` and stored as characters which cannot be written as ordinary variables
+pub const GENERATION: Var = 'G';
+pub const TIME: Var = 'T';
+pub const INDEX: Var = 'I';
+
 impl Expression {
-    pub fn eval(&self, context: &Context) -> Value {
-        match self {
+    pub fn eval(&self, context: &Context) -> Result<Value> {
+        Ok(match self {
             Expression::Value(x) => *x,
-            Expression::Var(x) => Self::lookup(context, *x),
-
-            Expression::Or(x, y) => {
-                Self::as_value(Self::as_bool(x.eval(context)) || Self::as_bool(y.eval(context)))
-            }
-            Expression::And(x, y) => {
-                Self::as_value(Self::as_bool(x.eval(context)) && Self::as_bool(y.eval(context)))
-            }
-            Expression::Eq(x, y) => Self::as_value(x.eval(context) == y.eval(context)),
-            Expression::GT(x, y) => Self::as_value(x.eval(context) > y.eval(context)),
-            Expression::LT(x, y) => Self::as_value(x.eval(context) < y.eval(context)),
-            Expression::GE(x, y) => Self::as_value(x.eval(context) >= y.eval(context)),
-            Expression::LE(x, y) => Self::as_value(x.eval(context) <= y.eval(context)),
-            Expression::Add(x, y) => x.eval(context) + y.eval(context),
-            Expression::Sub(x, y) => x.eval(context) - y.eval(context),
-            Expression::Mul(x, y) => x.eval(context) * y.eval(context),
-            Expression::Div(x, y) => x.eval(context) / y.eval(context),
-            Expression::Pow(x, y) => x.eval(context).powf(y.eval(context)),
+            Expression::Var(x) => Self::lookup(context, *x)?,
+
+            Expression::Or(x, y) => Self::as_value(x.eval_bool(context)? || y.eval_bool(context)?),
+            Expression::And(x, y) => Self::as_value(x.eval_bool(context)? && y.eval_bool(context)?),
+            Expression::Eq(x, y) => Self::as_value(x.eval(context)? == y.eval(context)?),
+            Expression::GT(x, y) => Self::as_value(x.eval(context)? > y.eval(context)?),
+            Expression::LT(x, y) => Self::as_value(x.eval(context)? < y.eval(context)?),
+            Expression::GE(x, y) => Self::as_value(x.eval(context)? >= y.eval(context)?),
+            Expression::LE(x, y) => Self::as_value(x.eval(context)? <= y.eval(context)?),
+            Expression::Add(x, y) => x.eval(context)? + y.eval(context)?,
+            Expression::Sub(x, y) => x.eval(context)? - y.eval(context)?,
+            Expression::Mul(x, y) => x.eval(context)? * y.eval(context)?,
+            Expression::Div(x, y) => x.eval(context)? / y.eval(context)?,
+            Expression::Pow(x, y) => x.eval(context)?.powf(y.eval(context)?),
+        })
+    }
+
+    /// The value of an expression which does not depend on any variable
+    pub fn constant(&self) -> Option<Value> {
+        self.eval(&Context::new()).ok()
+    }
+
+    /// True if the value of the expression depends on the variable `var`
+    pub fn reads(&self, var: Var) -> bool {
+        match self {
+            Expression::Var(x) => *x == var,
+            Expression::Value(_) => false,
+            Expression::Or(x, y)
+            | Expression::And(x, y)
+            | Expression::Eq(x, y)
+            | Expression::GT(x, y)
+            | Expression::LT(x, y)
+            | Expression::GE(x, y)
+            | Expression::LE(x, y)
+            | Expression::Add(x, y)
+            | Expression::Sub(x, y)
+            | Expression::Mul(x, y)
+            | Expression::Div(x, y)
+            | Expression::Pow(x, y) => x.reads(var) || y.reads(var),
         }
     }
 
-    pub fn eval_bool(&self, context: &Context) -> bool {
-        Self::as_bool(self.eval(context))
+    pub fn eval_bool(&self, context: &Context) -> Result<bool> {
+        self.eval(context).map(Self::as_bool)
     }
 
     fn as_bool(x: Value) -> bool {
@@ -82,8 +109,30 @@ impl Expression {
         }
     }
 
-    fn lookup(context: &Context, var: char) -> Value {
-        context.iter().find(|(x, _)| *x == var).unwrap().1 // to do : error handling
+    /// Later bindings of a variable shadow earlier ones
+    fn lookup(context: &Context, var: char) -> Result<Value> {
+        context
+            .iter()
+            .rev()
+            .find(|(x, _)| *x == var)
+            .map(|(_, value)| *value)
+            .ok_or(Error::UndefinedVariable(var))
+    }
+
+    /// How tightly the expression's operator binds, as in `PREC_CLIMBER`
+    fn precedence(&self) -> u8 {
+        match self {
+            Expression::Or(..) | Expression::And(..) => 1,
+            Expression::Eq(..)
+            | Expression::GT(..)
+            | Expression::LT(..)
+            | Expression::GE(..)
+            | Expression::LE(..) => 2,
+            Expression::Add(..) | Expression::Sub(..) => 3,
+            Expression::Mul(..) | Expression::Div(..) => 4,
+            Expression::Pow(..) => 5,
+            Expression::Var(_) | Expression::Value(_) => 6,
+        }
     }
 
     fn build_expression(expression: Pairs<Rule>) -> Expression {
@@ -93,6 +142,11 @@ impl Expression {
                 Rule::number => Expression::Value(pair.as_str().parse::<f32>().unwrap()),
                 Rule::expr => Self::build_expression(pair.into_inner()),
                 Rule::var => Expression::Var(pair.as_str().parse::<char>().unwrap()),
+                Rule::reserved => Expression::Var(match pair.as_str() {
+                    "gen" => GENERATION,
+                    "time" => TIME,
+                    _ => INDEX,
+                }),
                 _ => unreachable!(),
             },
             |lhs: Expression, op: Pair<Rule>, rhs: Expression| match op.as_rule() {
@@ -115,13 +169,10 @@ impl Expression {
 }
 
 impl FromStr for Expression {
-    type Err = Error<Rule>;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let result = ExpressionParser::parse(Rule::expression, s);
-        match result {
-            Ok(mut res) => Ok(Self::build_expression(res.next().unwrap().into_inner())),
-            Err(e) => panic!("parse failed: {}", e),
-        }
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let mut pairs = ExpressionParser::parse(Rule::expression, s)?;
+        Ok(Self::build_expression(pairs.next().unwrap().into_inner()))
     }
 }
 
@@ -133,22 +184,43 @@ impl Default for Expression {
 
 impl fmt::Display for Expression {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Expression::Value(x) => write!(f, "{}", x),
-            Expression::Var(x) => write!(f, "{}", x),
-            Expression::Or(x, y) => write!(f, "{}||{}", x, y),
-            Expression::And(x, y) => write!(f, "{}&&{}", x, y),
-            Expression::Eq(x, y) => write!(f, "{}=={}", x, y),
-            Expression::GT(x, y) => write!(f, "{}>{}", x, y),
-            Expression::LT(x, y) => write!(f, "{}<{}", x, y),
-            Expression::GE(x, y) => write!(f, "{}>={}", x, y),
-            Expression::LE(x, y) => write!(f, "{}<={}", x, y),
-            Expression::Add(x, y) => write!(f, "{}+{}", x, y),
-            Expression::Sub(x, y) => write!(f, "{}-{}", x, y),
-            Expression::Mul(x, y) => write!(f, "{}*{}", x, y),
-            Expression::Div(x, y) => write!(f, "{}/{}", x, y),
-            Expression::Pow(x, y) => write!(f, "{}^{}", x, y),
-        }
+        let (x, operator, y) = match self {
+            Expression::Value(x) => return write!(f, "{}", x),
+            Expression::Var(GENERATION) => return write!(f, "gen"),
+            Expression::Var(TIME) => return write!(f, "time"),
+            Expression::Var(INDEX) => return write!(f, "index"),
+            Expression::Var(x) => return write!(f, "{}", x),
+            Expression::Or(x, y) => (x, "||", y),
+            Expression::And(x, y) => (x, "&&", y),
+            Expression::Eq(x, y) => (x, "==", y),
+            Expression::GT(x, y) => (x, ">", y),
+            Expression::LT(x, y) => (x, "<", y),
+            Expression::GE(x, y) => (x, ">=", y),
+            Expression::LE(x, y) => (x, "<=", y),
+            Expression::Add(x, y) => (x, "+", y),
+            Expression::Sub(x, y) => (x, "-", y),
+            Expression::Mul(x, y) => (x, "*", y),
+            Expression::Div(x, y) => (x, "/", y),
+            Expression::Pow(x, y) => (x, "^", y),
+        };
+        // Operands which bind less tightly than the operator are bracketed, and so
+        // are those of equal precedence on the side opposite to the associativity
+        let precedence = self.precedence();
+        let (left, right) = match self {
+            Expression::Pow(..) => (precedence + 1, precedence),
+            _ => (precedence, precedence + 1),
+        };
+        write_operand(f, x, left)?;
+        write!(f, "{}", operator)?;
+        write_operand(f, y, right)
+    }
+}
+
+fn write_operand(f: &mut fmt::Formatter, operand: &Expression, precedence: u8) -> fmt::Result {
+    if operand.precedence() < precedence {
+        write!(f, "({})", operand)
+    } else {
+        write!(f, "{}", operand)
     }
 }
 
@@ -183,14 +255,14 @@ mod tests {
     fn eval_val() {
         let expr = Expression::Value(1.0);
         let context = &vec![];
-        assert_eq!(expr.eval(context), 1.0);
+        assert_eq!(expr.eval(context).unwrap(), 1.0);
     }
 
     #[test]
     fn eval_var() {
         let expr = Expression::Var('x');
         let context = &vec![('x', 2.0)];
-        assert_eq!(expr.eval(context), 2.0);
+        assert_eq!(expr.eval(context).unwrap(), 2.0);
     }
 
     #[test]
@@ -200,38 +272,74 @@ mod tests {
             Box::new(Expression::Var('y')),
         );
         let context = &vec![('x', 2.0), ('y', 3.7)];
-        assert_eq!(expr.eval(context), 5.7);
+        assert_eq!(expr.eval(context).unwrap(), 5.7);
     }
 
     #[test]
     fn eval_from_str() {
         let expr: Expression = "7".parse().ok().unwrap();
         let context = &vec![];
-        assert_eq!(expr.eval(context), 7.0);
+        assert_eq!(expr.eval(context).unwrap(), 7.0);
     }
     #[test]
     fn eval_expression_from_str() {
         let expr: Expression = "1+2.5*3".parse().ok().unwrap();
         let context = &vec![];
-        assert_eq!(expr.eval(context), 8.5);
+        assert_eq!(expr.eval(context).unwrap(), 8.5);
     }
     #[test]
     fn eval_expression2_from_str() {
         let expr: Expression = "((x+1)*y^2)/5".parse().ok().unwrap();
         let context = &vec![('x', 5.0), ('y', 3.0)];
-        assert_eq!(expr.eval(context), 10.8);
+        assert_eq!(expr.eval(context).unwrap(), 10.8);
     }
     #[test]
     fn eval_bool_expression_from_str() {
         let expr: Expression = "1+2>5*3".parse().ok().unwrap();
         let context = &vec![];
-        assert_eq!(expr.eval_bool(context), false);
+        assert!(!expr.eval_bool(context).unwrap());
     }
     #[test]
     fn eval_bool_expression_from_str2() {
         let expr: Expression = "1+2>5*3||2+2==4".parse().ok().unwrap();
         let context = &vec![];
-        assert_eq!(expr.eval_bool(context), true);
+        assert!(expr.eval_bool(context).unwrap());
+    }
+    #[test]
+    fn eval_undefined_variable() {
+        let expr: Expression = "x+y".parse().ok().unwrap();
+        let context = &vec![('x', 1.0)];
+        match expr.eval(context) {
+            Err(Error::UndefinedVariable('y')) => (),
+            other => panic!("expected undefined variable, got {:?}", other),
+        }
+    }
+    #[test]
+    fn display_round_trip() {
+        for source in &[
+            "(x+1)*y^2/5",
+            "x-(y-1)",
+            "2^3^2",
+            "(2^3)^2",
+            "x||(y&&z)",
+            "x+y>2==1",
+        ] {
+            let expr: Expression = source.parse().unwrap();
+            assert_eq!(&expr.to_string(), source);
+        }
+    }
+    #[test]
+    fn reserved_variables() {
+        let expr: Expression = "gen*10+index>time".parse().unwrap();
+        let context = &vec![(GENERATION, 2.0), (INDEX, 3.0), (TIME, 0.5)];
+        assert!(expr.eval_bool(context).unwrap());
+        assert_eq!(expr.to_string(), "gen*10+index>time");
+    }
+    #[test]
+    fn parse_invalid_expression() {
+        match "1+*2".parse::<Expression>() {
+            Err(Error::Expression(_)) => (),
+            other => panic!("expected expression error, got {:?}", other),
+        }
     }
-
 }
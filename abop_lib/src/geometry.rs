@@ -0,0 +1,304 @@
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// A coloured vertex of a triangle mesh, independent of any rendering backend
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Vertex {
+    pub pos: [f32; 3],
+    pub col: [f32; 3],
+    pub norm: [f32; 3],
+}
+
+/// A triangle mesh, as a list of distinct vertices and a list of indices into it,
+/// every three consecutive indices making one triangle. Vertices added with
+/// `append` are welded: a vertex equal to one already in the mesh, as where the
+/// turtle's tubes meet, is shared rather than stored again.
+///
+/// A mesh is written and read as a subset of the Wavefront OBJ format, so that
+/// the output of `abop -m` can be read back with `abop -g` or opened in other tools:
+///
+/// ```text
+/// # comment
+/// v 0 0 0 1 0.6 0      position followed by an optional colour
+/// vn 0 0 1             normal
+/// f 1//1 2//2 3//3     face as 1-based vertex//normal indices
+/// ```
+///
+/// When reading, faces with more than three vertices are split into a fan of
+/// triangles, texture coordinates are ignored, negative indices count back from
+/// the latest vertex and faces without normals get the normal of their plane.
+/// Statements other than `v`, `vn` and `f` are skipped.
+#[derive(Clone, Debug, Default)]
+pub struct Mesh {
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+    /// The index of each vertex, keyed by the bits of its attributes
+    welds: HashMap<[u32; 9], u32>,
+}
+
+const DEFAULT_COLOR: [f32; 3] = [1.0, 1.0, 1.0];
+
+impl Mesh {
+    pub fn new() -> Self {
+        Mesh {
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            welds: HashMap::new(),
+        }
+    }
+
+    /// The distinct vertices of the mesh
+    pub fn vertices(&self) -> &[Vertex] {
+        &self.vertices
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    /// The vertices of every triangle in turn, as they were appended
+    pub fn unindexed(&self) -> Vec<Vertex> {
+        self.indices
+            .iter()
+            .map(|&i| self.vertices[i as usize])
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// Adds triangles, every three vertices making one, and empties `vertices`
+    pub fn append(&mut self, vertices: &mut Vec<Vertex>) {
+        self.indices.reserve(vertices.len());
+        for vertex in vertices.drain(..) {
+            let index = self.weld(vertex);
+            self.indices.push(index);
+        }
+    }
+
+    fn weld(&mut self, vertex: Vertex) -> u32 {
+        let mut key = [0; 9];
+        let attributes = vertex.pos.iter().chain(&vertex.col).chain(&vertex.norm);
+        for (k, a) in key.iter_mut().zip(attributes) {
+            *k = a.to_bits();
+        }
+        let vertices = &mut self.vertices;
+        *self.welds.entry(key).or_insert_with(|| {
+            vertices.push(vertex);
+            (vertices.len() - 1) as u32
+        })
+    }
+}
+
+impl PartialEq for Mesh {
+    fn eq(&self, other: &Self) -> bool {
+        self.vertices == other.vertices && self.indices == other.indices
+    }
+}
+
+impl From<Vec<Vertex>> for Mesh {
+    fn from(mut vertices: Vec<Vertex>) -> Self {
+        let mut mesh = Mesh::new();
+        mesh.append(&mut vertices);
+        mesh
+    }
+}
+
+impl fmt::Display for Mesh {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for Vertex { pos, col, norm } in &self.vertices {
+            writeln!(
+                f,
+                "v {} {} {} {} {} {}",
+                pos[0], pos[1], pos[2], col[0], col[1], col[2]
+            )?;
+            writeln!(f, "vn {} {} {}", norm[0], norm[1], norm[2])?;
+        }
+        for triangle in self.indices.chunks(3) {
+            let [a, b, c] = [triangle[0] + 1, triangle[1] + 1, triangle[2] + 1];
+            writeln!(f, "f {}//{} {}//{} {}//{}", a, a, b, b, c, c)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Mesh {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut positions: Vec<([f32; 3], [f32; 3])> = Vec::new();
+        let mut normals: Vec<[f32; 3]> = Vec::new();
+        let mut mesh = Mesh::new();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut words = line.split_whitespace();
+            let format_error = |message: &str| Error::Format {
+                line: n + 1,
+                message: message.to_string(),
+            };
+            match words.next() {
+                Some("v") => {
+                    let values = parse_numbers(words, n)?;
+                    match values[..] {
+                        [x, y, z] => positions.push(([x, y, z], DEFAULT_COLOR)),
+                        [x, y, z, r, g, b, ..] => positions.push(([x, y, z], [r, g, b])),
+                        _ => return Err(format_error("expected 'v x y z [r g b]'")),
+                    }
+                }
+                Some("vn") => match parse_numbers(words, n)?[..] {
+                    [x, y, z] => normals.push([x, y, z]),
+                    _ => return Err(format_error("expected 'vn x y z'")),
+                },
+                Some("f") => {
+                    let corners = words
+                        .map(|word| {
+                            let mut indices = word.split('/');
+                            let v = resolve(indices.next(), positions.len(), n)?;
+                            let vn = resolve(indices.nth(1), normals.len(), n)?;
+                            match v {
+                                Some(v) => Ok((v, vn)),
+                                None => Err(format_error("missing vertex index")),
+                            }
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    if corners.len() < 3 {
+                        return Err(format_error("a face needs at least three vertices"));
+                    }
+                    for i in 1..corners.len() - 1 {
+                        let triangle = [corners[0], corners[i], corners[i + 1]];
+                        let [a, b, c] = triangle.map(|(v, _)| positions[v].0);
+                        let face_normal = plane_normal(a, b, c);
+                        mesh.append(
+                            &mut triangle
+                                .iter()
+                                .map(|&(v, vn)| Vertex {
+                                    pos: positions[v].0,
+                                    col: positions[v].1,
+                                    norm: vn.map_or(face_normal, |vn| normals[vn]),
+                                })
+                                .collect(),
+                        );
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(mesh)
+    }
+}
+
+fn parse_numbers<'a, I>(words: I, line: usize) -> Result<Vec<f32>>
+where
+    I: Iterator<Item = &'a str>,
+{
+    words
+        .map(|word| {
+            word.parse().map_err(|e| Error::Token {
+                token: format!("{} (line {})", word, line + 1),
+                source: Box::new(e),
+            })
+        })
+        .collect()
+}
+
+/// Converts a 1-based or negative OBJ index into an index into a list of `len` items
+fn resolve(index: Option<&str>, len: usize, line: usize) -> Result<Option<usize>> {
+    let index = match index {
+        None | Some("") => return Ok(None),
+        Some(index) => index,
+    };
+    let i: i64 = index.parse().map_err(|e| Error::Token {
+        token: format!("{} (line {})", index, line + 1),
+        source: Box::new(e),
+    })?;
+    let resolved = if i < 0 { len as i64 + i } else { i - 1 };
+    if resolved < 0 || resolved >= len as i64 {
+        return Err(Error::Format {
+            line: line + 1,
+            message: format!("index {} out of range", i),
+        });
+    }
+    Ok(Some(resolved as usize))
+}
+
+fn plane_normal(a: [f32; 3], b: [f32; 3], c: [f32; 3]) -> [f32; 3] {
+    let u = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+    let v = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+    let n = [
+        u[1] * v[2] - u[2] * v[1],
+        u[2] * v[0] - u[0] * v[2],
+        u[0] * v[1] - u[1] * v[0],
+    ];
+    let length = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+    if length > 0.0 {
+        [n[0] / length, n[1] / length, n[2] / length]
+    } else {
+        [0.0, 0.0, 1.0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(x: f32) -> Vertex {
+        Vertex {
+            pos: [x, 0.5, -1.25],
+            col: [1.0, 0.6, 0.0],
+            norm: [0.0, 1.0, 0.0],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mesh = Mesh::from((0..6).map(|i| vertex(i as f32)).collect::<Vec<_>>());
+        let parsed: Mesh = mesh.to_string().parse().unwrap();
+        assert_eq!(parsed, mesh);
+    }
+
+    #[test]
+    fn read_quad_without_normals() {
+        let mesh: Mesh = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1 2 3 4\n"
+            .parse()
+            .unwrap();
+        assert_eq!(mesh.vertices().len(), 4);
+        assert_eq!(mesh.indices(), &[0, 1, 2, 0, 2, 3]);
+        assert!(mesh.vertices().iter().all(|v| v.norm == [0.0, 0.0, 1.0]));
+        assert_eq!(mesh.unindexed()[5].pos, [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn weld_shared_vertices() {
+        let mut triangles: Vec<_> = [0, 1, 2, 2, 1, 3]
+            .iter()
+            .map(|&i| vertex(i as f32))
+            .collect();
+        let mesh = Mesh::from(triangles.clone());
+        assert_eq!(mesh.vertices().len(), 4);
+        assert_eq!(mesh.indices(), &[0, 1, 2, 2, 1, 3]);
+        assert_eq!(mesh.unindexed(), triangles);
+        let text = mesh.to_string();
+        assert_eq!(text.lines().filter(|l| l.starts_with("v ")).count(), 4);
+        assert!(text.ends_with("f 3//3 2//2 4//4\n"));
+        assert_eq!(text.parse::<Mesh>().unwrap(), mesh);
+        triangles[4].norm = [0.0, 0.0, 1.0];
+        assert_eq!(Mesh::from(triangles).vertices().len(), 5);
+    }
+
+    #[test]
+    fn read_negative_indices() {
+        let mesh: Mesh = "v 0 0 0\nv 1 0 0\nv 1 1 0\nf -3 -2 -1\n".parse().unwrap();
+        assert_eq!(mesh.vertices()[0].pos, [0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn read_index_out_of_range() {
+        match "v 0 0 0\nf 1 2 3\n".parse::<Mesh>() {
+            Err(Error::Format { line: 2, .. }) => (),
+            other => panic!("expected format error, got {:?}", other),
+        }
+    }
+}
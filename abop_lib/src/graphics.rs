@@ -3,6 +3,8 @@ extern crate luminance_derive;
 extern crate luminance_glfw;
 
 extern crate nalgebra_glm as glm;
+use crate::error::Result;
+use crate::geometry::{self, Mesh};
 use glm::Vec3;
 use luminance::context::GraphicsContext;
 use luminance::framebuffer::Framebuffer;
@@ -32,10 +34,10 @@ pub enum Semantics {
 
 #[derive(Clone, Copy, Debug, PartialEq, Vertex)]
 #[vertex(sem = "Semantics")]
-pub struct Vertex {
-    pub pos: VertexPosition,
-    pub col: VertexColor,
-    pub norm: VertexNormal,
+struct GlVertex {
+    pos: VertexPosition,
+    col: VertexColor,
+    norm: VertexNormal,
 }
 
 #[derive(Debug, UniformInterface)]
@@ -68,13 +70,34 @@ pub struct Application<T> {
 }
 
 pub trait Displayable {
-    fn vertices(&mut self) -> Vec<Vertex>;
-    fn update(&mut self) {}
+    fn mesh(&mut self) -> Result<Mesh>;
+    fn update(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn step_back(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn next_model(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn previous_model(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
-impl Displayable for Vec<Vertex> {
-    fn vertices(&mut self) -> Vec<Vertex> {
-        self.clone()
+impl Displayable for Mesh {
+    fn mesh(&mut self) -> Result<Mesh> {
+        Ok(self.clone())
+    }
+}
+
+impl From<&geometry::Vertex> for GlVertex {
+    fn from(v: &geometry::Vertex) -> Self {
+        GlVertex {
+            pos: VertexPosition::new(v.pos),
+            col: VertexColor::new(v.col),
+            norm: VertexNormal::new(v.norm),
+        }
     }
 }
 
@@ -86,7 +109,7 @@ where
     const DEFAULT_HEIGHT: u32 = 540;
     const ROTATION_SPEED: f32 = 0.005;
 
-    pub fn new(mut obj: T) -> Self {
+    pub fn new(mut obj: T) -> Result<Self> {
         let mut surface = GlfwSurface::new(
             WindowDim::Windowed(Self::DEFAULT_WIDTH, Self::DEFAULT_HEIGHT),
             "LSystem",
@@ -99,9 +122,9 @@ where
                 .expect("Shader program creation failed");
 
         let back_buffer = Framebuffer::back_buffer(surface.size());
-        let tess = Self::make_tess(&mut obj, &mut surface);
+        let tess = Self::make_tess(&mut obj, &mut surface)?;
 
-        Application {
+        Ok(Application {
             surface,
             program,
             back_buffer,
@@ -111,11 +134,11 @@ where
             timer: Timer::new(),
             obj,
             tess,
-        }
+        })
     }
 
-    pub fn run(&mut self) {
-        while self.handle_input() {
+    pub fn run(&mut self) -> Result<()> {
+        while self.handle_input()? {
             let t = self.timer.elapsed();
             self.model = glm::rotate(
                 &self.model,
@@ -125,17 +148,36 @@ where
 
             self.render();
         }
+        Ok(())
     }
 
-    fn make_tess(obj: &mut T, surface: &mut GlfwSurface) -> Tess {
-        TessBuilder::new(surface)
-            .add_vertices(obj.vertices())
+    fn make_tess(obj: &mut T, surface: &mut GlfwSurface) -> Result<Tess> {
+        let mesh = obj.mesh()?;
+        Ok(TessBuilder::new(surface)
+            .add_vertices(
+                mesh.vertices()
+                    .iter()
+                    .map(GlVertex::from)
+                    .collect::<Vec<_>>(),
+            )
+            .set_indices(mesh.indices())
             .set_mode(Mode::Triangle)
             .build()
-            .unwrap()
+            .unwrap())
+    }
+
+    /// Applies a change to the displayed object and rebuilds the tessellation. A change
+    /// which fails, such as a generation going past the limits, is reported and the
+    /// previous model stays on screen.
+    fn change(&mut self, f: fn(&mut T) -> Result<()>) -> Result<()> {
+        match f(&mut self.obj) {
+            Ok(()) => self.tess = Self::make_tess(&mut self.obj, &mut self.surface)?,
+            Err(e) => eprintln!("{}", e),
+        }
+        Ok(())
     }
 
-    fn handle_input(&mut self) -> bool {
+    fn handle_input(&mut self) -> Result<bool> {
         let press = |action| action == Action::Press || action == Action::Repeat;
         let events: Vec<WindowEvent> = self.surface.poll_events().collect();
 
@@ -147,13 +189,21 @@ where
                     Key::D => self.camera.turn_right(),
                     Key::A => self.camera.turn_left(),
                     Key::Space => {
-                        self.obj.update();
-                        self.tess = Self::make_tess(&mut self.obj, &mut self.surface);
+                        self.change(T::update)?;
+                    }
+                    Key::Backspace => {
+                        self.change(T::step_back)?;
+                    }
+                    Key::PageDown => {
+                        self.change(T::next_model)?;
+                    }
+                    Key::PageUp => {
+                        self.change(T::previous_model)?;
                     }
                     _ => (),
                 },
                 WindowEvent::Close | WindowEvent::Key(Key::Escape, _, Action::Release, _) => {
-                    return false
+                    return Ok(false)
                 }
                 WindowEvent::FramebufferSize(width, height) => {
                     self.resize_window(width, height);
@@ -161,7 +211,7 @@ where
                 _ => (),
             }
         }
-        true
+        Ok(true)
     }
 
     fn resize_window(&mut self, width: i32, height: i32) {
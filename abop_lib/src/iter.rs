@@ -26,10 +26,7 @@ where
             None => None,
             Some(next) => {
                 let before = self.prev.clone();
-                let after: Option<T> = match self.iter.peek() {
-                    None => None,
-                    Some(x) => Some((*x).clone()),
-                };
+                let after: Option<T> = self.iter.peek().cloned();
                 self.prev = Some(next.clone());
                 Some((before, next, after))
             }
@@ -68,5 +65,4 @@ mod tests {
             ]
         );
     }
-
 }
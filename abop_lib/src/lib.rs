@@ -3,11 +3,26 @@ extern crate lazy_static;
 extern crate approx;
 extern crate num_traits;
 
+pub mod analysis;
+pub mod catalog;
 pub mod cli;
 pub mod config;
+pub mod contour;
+pub mod error;
 pub mod expr;
+pub mod geometry;
+#[cfg(feature = "viewer")]
 pub mod graphics;
 pub mod iter;
+pub mod limits;
 pub mod lsys;
+pub mod palette;
 pub mod parser;
+pub mod polygon;
+pub mod provenance;
+pub mod stats;
+pub mod surface;
+pub mod symbol;
 pub mod turtle;
+
+pub use error::{Error, Result};
@@ -0,0 +1,151 @@
+use crate::error::{Error, Result};
+use crate::lsys::{ActualParam, Element};
+use std::fmt;
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// Bounds on the resources used to derive a single generation. A derivation which
+/// goes past one of them fails with `Error::LimitExceeded` rather than running
+/// until the machine is out of memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    /// The number of elements in the string
+    pub max_length: Option<usize>,
+    /// An estimate of the bytes used by the string
+    pub max_memory: Option<usize>,
+    /// The wall-clock time spent rewriting
+    pub max_time: Option<Duration>,
+}
+
+impl Limits {
+    pub fn unlimited() -> Self {
+        Limits {
+            max_length: None,
+            max_memory: None,
+            max_time: None,
+        }
+    }
+}
+
+/// By default only the memory estimate is bounded, at 1 GiB
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_memory: Some(1 << 30),
+            ..Limits::unlimited()
+        }
+    }
+}
+
+/// The limit which a derivation went past
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Length(usize),
+    Memory(usize),
+    Time(Duration),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Length(length) => write!(f, "the maximum length of {} elements", length),
+            Limit::Memory(bytes) => write!(f, "the memory limit of {} MiB", bytes >> 20),
+            Limit::Time(time) => write!(f, "the time limit of {:?}", time),
+        }
+    }
+}
+
+/// Keeps count of the resources used while a generation is being derived
+pub(crate) struct Guard<'a> {
+    limits: &'a Limits,
+    generation: usize,
+    start: Instant,
+    length: usize,
+    memory: usize,
+}
+
+impl<'a> Guard<'a> {
+    pub fn new(limits: &'a Limits, generation: usize) -> Self {
+        Guard {
+            limits,
+            generation,
+            start: Instant::now(),
+            length: 0,
+            memory: 0,
+        }
+    }
+
+    /// Accounts for elements added to the generation
+    pub fn add<'e, I>(&mut self, elements: I) -> Result<()>
+    where
+        I: IntoIterator<Item = &'e Element<ActualParam>>,
+    {
+        for element in elements {
+            self.length += 1;
+            self.memory += mem::size_of::<Element<ActualParam>>()
+                + element.params.len() * mem::size_of::<ActualParam>();
+        }
+        match self.limits {
+            Limits {
+                max_length: Some(max),
+                ..
+            } if self.length > *max => Err(self.exceeded(Limit::Length(*max))),
+            Limits {
+                max_memory: Some(max),
+                ..
+            } if self.memory > *max => Err(self.exceeded(Limit::Memory(*max))),
+            Limits {
+                max_time: Some(max),
+                ..
+            } if self.start.elapsed() > *max => Err(self.exceeded(Limit::Time(*max))),
+            _ => Ok(()),
+        }
+    }
+
+    fn exceeded(&self, limit: Limit) -> Error {
+        Error::LimitExceeded {
+            generation: self.generation,
+            limit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_lsys;
+
+    #[test]
+    fn stop_at_maximum_length() {
+        let mut lsystem = parse_lsys("F\nF=FF").unwrap();
+        lsystem.set_limits(Limits {
+            max_length: Some(100),
+            ..Limits::unlimited()
+        });
+        match lsystem.goto(10) {
+            Err(Error::LimitExceeded {
+                generation: 7,
+                limit: Limit::Length(100),
+            }) => (),
+            other => panic!("expected limit error, got {:?}", other),
+        }
+        assert_eq!(lsystem.current_generation(), 6);
+        assert_eq!(lsystem.current.to_string().len(), 64);
+    }
+
+    #[test]
+    fn stop_at_memory_limit() {
+        let mut lsystem = parse_lsys("F(1)\nF(x)=F(x)F(x)").unwrap();
+        lsystem.set_limits(Limits {
+            max_memory: Some(1 << 20),
+            ..Limits::unlimited()
+        });
+        match lsystem.goto(40) {
+            Err(Error::LimitExceeded {
+                limit: Limit::Memory(_),
+                ..
+            }) => (),
+            other => panic!("expected limit error, got {:?}", other),
+        }
+    }
+}
@@ -1,7 +1,16 @@
-use crate::expr::{Context, Expression};
+use crate::error::{Error, Result};
+use crate::expr::{Context, Expression, GENERATION, INDEX, TIME};
+use crate::limits::{Guard, Limits};
+use crate::parser;
+use crate::provenance::{Origin, Provenance};
+use crate::stats::GenerationStats;
+use crate::symbol;
 use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::collections::VecDeque;
 use std::fmt;
 use std::iter::FromIterator;
+use std::str::FromStr;
 
 pub type Symbol = char;
 pub type ActualParam = f32;
@@ -11,11 +20,41 @@ pub type FormalParam = char;
 pub struct LSystem {
     pub current: LString,
     next: LString,
+    axiom: LString,
     productions: Vec<Production>,
-    count: u8,
+    count: usize,
+    generation: usize,
+    seed: u64,
+    /// The generations just before `current`, the latest at the back
+    history: VecDeque<LString>,
+    history_limit: usize,
+    /// What the step which derived `current` did, if known
+    step: Option<Step>,
+    limits: Limits,
+    /// The origin of every module, when provenance tracking is on
+    provenance: Option<Provenance>,
+    selection: Selection,
+    /// The time which passes with each generation, for the `time` variable
+    time_step: f32,
 }
 
-#[derive(Debug, Clone)]
+/// How the probabilities of stochastic productions choose among the productions
+/// which match an element
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Selection {
+    /// The probabilities are weights, normalized over the matching productions so
+    /// that one of them is always chosen. If the first matching production has the
+    /// default probability of 1 it is chosen, as in a deterministic lsystem.
+    Normalized,
+    /// The probabilities are added up in order and the element is left unchanged
+    /// when the draw falls past their total, as in earlier versions
+    Legacy,
+}
+
+/// The number of past generations kept by default, earlier ones are replayed
+const HISTORY_LIMIT: usize = 16;
+
+#[derive(Debug, Clone, Default)]
 pub struct LString(Vec<Element<ActualParam>>);
 
 #[derive(Debug, Clone)]
@@ -27,67 +66,595 @@ pub struct Element<T> {
 #[derive(Debug)]
 pub struct Production {
     pred: Element<FormalParam>,
+    /// Variables computed from the parameters, in order, before the condition
+    locals: Vec<(FormalParam, Expression)>,
     condition: Option<Expression>,
-    probability: f32,
+    /// The weight of the production, which may depend on the predecessor's parameters
+    probability: Expression,
     succ: Vec<Element<Expression>>,
 }
 
 impl LSystem {
     pub fn new(axiom: LString, productions: Vec<Production>) -> Self {
         LSystem {
-            current: axiom,
+            current: axiom.clone(),
             next: LString::new(),
+            axiom,
             productions,
             count: 0,
+            generation: 0,
+            seed: rand::random(),
+            history: VecDeque::new(),
+            history_limit: HISTORY_LIMIT,
+            step: None,
+            limits: Limits::default(),
+            provenance: None,
+            selection: Selection::Normalized,
+            time_step: 1.0,
         }
     }
 
-    pub fn generate(&mut self) {
-        for element in &self.current {
-            match self.select_production(&element) {
-                Some(production) => {
-                    let mut lstring = production.apply(&element);
-                    self.next.append(&mut lstring)
+    pub fn axiom(&self) -> &LString {
+        &self.axiom
+    }
+
+    pub fn productions(&self) -> &[Production] {
+        &self.productions
+    }
+
+    /// The number of times the axiom has been rewritten to reach `current`
+    pub fn current_generation(&self) -> usize {
+        self.generation
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Derives generation `n` from the axiom depth-first: each element is rewritten
+    /// `n` times recursively and the result emitted element by element, so that no
+    /// generation is ever stored whole. The memory used grows with `n` and the length
+    /// of the successors rather than with the length of the string.
+    ///
+    /// Stochastic productions draw in the order of a whole generation, so they cannot
+    /// be streamed; conditions are allowed, as they only depend on the element and the
+    /// reserved variables.
+    pub fn stream(&self, n: usize) -> Result<Stream<'_>> {
+        if let Some(production) = self.productions.iter().find(|p| !p.is_certain()) {
+            return Err(Error::Unsupported(format!(
+                "streaming needs a deterministic lsystem, but the productions for '{}' are stochastic",
+                production.pred.symbol
+            )));
+        }
+        Ok(Stream {
+            lsystem: self,
+            n,
+            stack: vec![(self.axiom.0.clone().into_iter(), n)],
+            counters: vec![0; n + 1],
+            timed: self.productions.iter().any(Production::is_timed),
+        })
+    }
+
+    /// Statistics of the current generation and of the step which derived it
+    pub fn stats(&self) -> Result<GenerationStats> {
+        let step = match (&self.step, self.generation) {
+            (Some(step), _) => step.clone(),
+            (None, 0) => Step {
+                rewrites: vec![0; self.productions.len()],
+                undecided: false,
+            },
+            (None, k) => {
+                self.rewrite(&self.generation(k - 1)?, k - 1, &mut LString::new(), None)?
+            }
+        };
+        let fixed_point = self.ends_at(&step);
+        Ok(GenerationStats::new(
+            self.generation,
+            &self.current,
+            step.rewrites,
+            fixed_point,
+        ))
+    }
+
+    /// True when no production fired in the step which derived `current`, and none
+    /// could fire on a later draw, so that every later generation is the same.
+    pub fn is_fixed_point(&self) -> bool {
+        match &self.step {
+            Some(step) => self.ends_at(step),
+            None => false,
+        }
+    }
+
+    /// Whether nothing changes after `step`, which derived `current`. A production
+    /// whose choice reads the generation or the time may still fire later on.
+    fn ends_at(&self, step: &Step) -> bool {
+        self.generation > 0
+            && step.is_fixed_point()
+            && !self.productions.iter().any(Production::is_timed)
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Bounds the resources used to derive each generation
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn time_step(&self) -> f32 {
+        self.time_step
+    }
+
+    /// Sets the time which passes with each generation and develops the lsystem
+    /// again, from the axiom to the current generation.
+    pub fn set_time_step(&mut self, time_step: f32) -> Result<()> {
+        let generation = self.generation;
+        self.time_step = time_step;
+        self.reset();
+        self.goto(generation)
+    }
+
+    pub fn selection(&self) -> Selection {
+        self.selection
+    }
+
+    /// Sets how stochastic productions are chosen and develops the lsystem again,
+    /// from the axiom to the current generation.
+    pub fn set_selection(&mut self, selection: Selection) -> Result<()> {
+        let generation = self.generation;
+        self.selection = selection;
+        self.reset();
+        self.goto(generation)
+    }
+
+    /// Finds productions which are likely not to mean what was intended: stochastic
+    /// productions for one predecessor whose probabilities do not add up to 1 or which
+    /// mix conditions with probabilities, so that the weights depend on which
+    /// conditions hold, and unconditional productions hidden by an earlier one.
+    /// Returns a message for each.
+    pub fn validate(&self) -> Vec<String> {
+        let mut groups: Vec<(&Element<FormalParam>, Vec<&Production>)> = Vec::new();
+        for production in &self.productions {
+            let pred = &production.pred;
+            match groups
+                .iter_mut()
+                .find(|(p, _)| p.symbol == pred.symbol && p.params.len() == pred.params.len())
+            {
+                Some((_, group)) => group.push(production),
+                None => groups.push((pred, vec![production])),
+            }
+        }
+        let mut warnings = Vec::new();
+        for (pred, group) in groups {
+            let stochastic = group.iter().any(|p| !p.is_certain());
+            if !stochastic {
+                if group.iter().filter(|p| p.condition.is_none()).count() > 1 {
+                    warnings.push(format!(
+                        "only the first of the productions for {} without a condition is used",
+                        pred
+                    ));
+                }
+                continue;
+            }
+            if group.iter().any(|p| p.condition.is_some()) {
+                warnings.push(format!(
+                    "the productions for {} mix conditions and probabilities, the probabilities \
+                     are weighed against those of the productions whose conditions hold",
+                    pred
+                ));
+                continue;
+            }
+            let weights: Option<Vec<f32>> =
+                group.iter().map(|p| p.probability.constant()).collect();
+            let total: f32 = match (weights, self.selection) {
+                (Some(weights), _) => weights.iter().sum(),
+                (None, Selection::Normalized) => continue,
+                (None, Selection::Legacy) => {
+                    warnings.push(format!(
+                        "the probabilities of the productions for {} depend on its parameters, \
+                         {} is left unchanged whenever they add up to less than 1",
+                        pred, pred.symbol
+                    ));
+                    continue;
                 }
-                None => self.next.push(element.clone()),
+            };
+            if group.iter().all(|p| p.condition.is_none()) && (total - 1.0).abs() > 1e-4 {
+                let effect = match self.selection {
+                    Selection::Normalized => "they are used as weights".to_string(),
+                    Selection::Legacy if total < 1.0 => format!(
+                        "{} is left unchanged {:.0}% of the time",
+                        pred.symbol,
+                        (1.0 - total) * 100.0
+                    ),
+                    Selection::Legacy => "the productions past 1 are chosen less often".to_string(),
+                };
+                warnings.push(format!(
+                    "the probabilities of the productions for {} add up to {}, {}",
+                    pred, total, effect
+                ));
             }
         }
-        std::mem::swap(&mut self.current, &mut self.next);
-        self.next.clear();
+        warnings
     }
 
-    fn select_production(&self, element: &Element<ActualParam>) -> Option<&Production> {
-        let matches: Vec<&Production> = self
-            .productions
-            .iter()
-            .filter(|x| x.matches(&element))
-            .collect();
-        let r: f32 = rand::thread_rng().gen();
+    /// The origins of the modules of every generation up to `current`, if tracked
+    pub fn provenance(&self) -> Option<&Provenance> {
+        self.provenance.as_ref()
+    }
+
+    /// Turns provenance tracking on or off. Turning it on develops the lsystem again
+    /// from the axiom, to record the generations up to the current one.
+    pub fn set_provenance(&mut self, on: bool) -> Result<()> {
+        match (on, &self.provenance) {
+            (true, None) => {
+                let generation = self.generation;
+                self.provenance = Some(Provenance::new());
+                self.reset();
+                self.goto(generation)
+            }
+            (false, _) => {
+                self.provenance = None;
+                Ok(())
+            }
+            (true, Some(_)) => Ok(()),
+        }
+    }
+
+    /// Sets how many past generations are kept. Older generations are developed
+    /// again from the axiom when they are asked for.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
+
+    /// Sets the seed for stochastic productions and develops the lsystem again,
+    /// from the axiom to the current generation.
+    pub fn reseed(&mut self, seed: u64) -> Result<()> {
+        let generation = self.generation;
+        self.seed = seed;
+        self.reset();
+        self.goto(generation)
+    }
+
+    /// Returns to the axiom
+    pub fn reset(&mut self) {
+        self.current = self.axiom.clone();
+        self.generation = 0;
+        self.history.clear();
+        self.step = None;
+        if let Some(provenance) = &mut self.provenance {
+            provenance.truncate(0);
+        }
+    }
+
+    /// Returns generation `k` without changing `current`. Past generations come
+    /// from the history when they are recent enough, otherwise they are replayed
+    /// from the axiom; later generations are developed from `current`.
+    pub fn generation(&self, k: usize) -> Result<LString> {
+        let earliest = self.generation - self.history.len();
+        let (mut lstring, mut n) = if k >= self.generation {
+            (self.current.clone(), self.generation)
+        } else if k >= earliest {
+            return Ok(self.history[k - earliest].clone());
+        } else {
+            (self.axiom.clone(), 0)
+        };
+        let mut next = LString::new();
+        while n < k {
+            self.rewrite(&lstring, n, &mut next, None)?;
+            std::mem::swap(&mut lstring, &mut next);
+            n += 1;
+        }
+        Ok(lstring)
+    }
+
+    /// Goes back to the previous generation, returns false at the axiom.
+    pub fn step_back(&mut self) -> Result<bool> {
+        if self.generation == 0 {
+            return Ok(false);
+        }
+        self.current = match self.history.pop_back() {
+            Some(previous) => previous,
+            None => self.generation(self.generation - 1)?,
+        };
+        self.generation -= 1;
+        self.step = None;
+        if let Some(provenance) = &mut self.provenance {
+            provenance.truncate(self.generation);
+        }
+        Ok(true)
+    }
+
+    /// Develops the lsystem to generation `n`, going back through the history or
+    /// replaying from the axiom if `n` is earlier than the current generation.
+    pub fn goto(&mut self, n: usize) -> Result<()> {
+        if n < self.generation {
+            let earliest = self.generation - self.history.len();
+            if n >= earliest {
+                self.history.truncate(n - earliest + 1);
+                self.current = self.history.pop_back().unwrap();
+            } else {
+                self.current = self.generation(n)?;
+                self.history.clear();
+            }
+            self.generation = n;
+            self.step = None;
+            if let Some(provenance) = &mut self.provenance {
+                provenance.truncate(n);
+            }
+        }
+        while self.generation < n {
+            self.generate()?;
+        }
+        Ok(())
+    }
+
+    pub fn generate(&mut self) -> Result<()> {
+        let mut next = match self.history.len() {
+            len if len > 0 && len >= self.history_limit => self.history.pop_front().unwrap(),
+            _ => std::mem::take(&mut self.next),
+        };
+        let mut origins = self.provenance.as_ref().map(|_| Vec::new());
+        let step = self.rewrite(&self.current, self.generation, &mut next, origins.as_mut())?;
+        let previous = std::mem::replace(&mut self.current, next);
+        if self.history_limit > 0 {
+            self.history.push_back(previous);
+        } else {
+            self.next = previous;
+        }
+        self.generation += 1;
+        self.step = Some(step);
+        if let (Some(provenance), Some(origins)) = (&mut self.provenance, origins) {
+            provenance.push(origins);
+        }
+        Ok(())
+    }
+
+    /// Rewrites `lstring`, which is generation number `generation`, into `next`.
+    /// Returns what the step did, and records the origin of each element of `next` in
+    /// `origins` if given.
+    fn rewrite(
+        &self,
+        lstring: &LString,
+        generation: usize,
+        next: &mut LString,
+        mut origins: Option<&mut Vec<Origin>>,
+    ) -> Result<Step> {
+        let mut rng = self.rng(generation);
+        let mut rewrites = vec![0; self.productions.len()];
+        let mut undecided = false;
+        let mut guard = Guard::new(&self.limits, generation + 1);
+        next.clear();
+        for (parent, element) in lstring.into_iter().enumerate() {
+            let globals = self.globals(generation, parent);
+            let production = self.select_production(element, &globals, &mut rng)?;
+            match production {
+                Some(i) => {
+                    let mut lstring = self.productions[i].apply(element, &globals)?;
+                    guard.add(&lstring)?;
+                    if let Some(origins) = &mut origins {
+                        let origin = Origin { parent, production };
+                        origins.resize(origins.len() + lstring.len(), origin);
+                    }
+                    next.append(&mut lstring);
+                    rewrites[i] += 1;
+                }
+                None => {
+                    undecided = undecided || self.may_fire(element, &globals)?;
+                    guard.add(std::iter::once(element))?;
+                    if let Some(origins) = &mut origins {
+                        origins.push(Origin { parent, production });
+                    }
+                    next.push(element.clone())
+                }
+            }
+        }
+        Ok(Step {
+            rewrites,
+            undecided,
+        })
+    }
+
+    /// Each generation draws from its own generator, seeded from the lsystem's seed
+    /// and the generation number, so replaying a stochastic lsystem repeats its choices.
+    /// The two are mixed so that neighbouring seeds do not replay each other's
+    /// generations shifted by one.
+    fn rng(&self, generation: usize) -> StdRng {
+        StdRng::seed_from_u64(splitmix64(self.seed ^ (generation as u64).rotate_left(32)))
+    }
+
+    /// The reserved variables for element `index` of generation `generation`
+    pub(crate) fn globals(&self, generation: usize, index: usize) -> Context {
+        vec![
+            (GENERATION, generation as f32),
+            (TIME, generation as f32 * self.time_step),
+            (INDEX, index as f32),
+        ]
+    }
+
+    fn select_production(
+        &self,
+        element: &Element<ActualParam>,
+        globals: &Context,
+        rng: &mut impl Rng,
+    ) -> Result<Option<usize>> {
+        let mut matches: Vec<(usize, f32)> = Vec::new();
+        for (i, production) in self.productions.iter().enumerate() {
+            if production.matches(element, globals)? {
+                matches.push((i, production.weight(element, globals)?));
+            }
+        }
+        let r: f32 = rng.gen();
+        let total: f32 = match (self.selection, matches.first()) {
+            // As in a deterministic lsystem, the first production which matches wins
+            // unless it is stochastic
+            (Selection::Normalized, Some(&(i, _))) if self.productions[i].is_certain() => {
+                return Ok(Some(i))
+            }
+            (Selection::Normalized, _) => matches.iter().map(|(_, weight)| weight).sum(),
+            (Selection::Legacy, _) => 1.0,
+        };
+        let r = r * total;
         let mut t: f32 = 0.0;
-        for production in matches {
-            t += production.probability;
+        for &(i, weight) in &matches {
+            t += weight;
             if r < t {
-                return Some(production);
+                return Ok(Some(i));
+            }
+        }
+        // Rounding may leave the draw just past the total of the weights
+        match self.selection {
+            Selection::Normalized => Ok(matches
+                .into_iter()
+                .rev()
+                .find(|&(_, weight)| weight > 0.0)
+                .map(|(i, _)| i)),
+            Selection::Legacy => Ok(None),
+        }
+    }
+
+    /// Whether a stochastic production which did not fire for `element` could fire on
+    /// another draw, which happens only with `Selection::Legacy` when the
+    /// probabilities add up to less than 1
+    fn may_fire(&self, element: &Element<ActualParam>, globals: &Context) -> Result<bool> {
+        if self.selection != Selection::Legacy {
+            return Ok(false);
+        }
+        for production in &self.productions {
+            if production.matches(element, globals)? && production.weight(element, globals)? > 0.0 {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// What one derivation step did
+#[derive(Clone, Debug)]
+struct Step {
+    /// How many elements each production rewrote
+    rewrites: Vec<usize>,
+    /// An element was left as it was by a stochastic production which may still
+    /// rewrite it in a later step
+    undecided: bool,
+}
+
+impl Step {
+    fn is_fixed_point(&self) -> bool {
+        !self.undecided && self.rewrites.iter().all(|&n| n == 0)
+    }
+}
+
+/// One step of the SplitMix64 generator, which scatters nearby inputs across all
+/// 64 bits
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// The elements of a generation derived depth-first, see `LSystem::stream`
+pub struct Stream<'a> {
+    lsystem: &'a LSystem,
+    n: usize,
+    /// The elements still to be emitted at each level of the derivation, with the
+    /// number of times each of them is still to be rewritten
+    stack: Vec<(std::vec::IntoIter<Element<ActualParam>>, usize)>,
+    /// The number of elements of each generation passed so far, which gives the
+    /// index of the next one
+    counters: Vec<usize>,
+    /// Whether a production reads the generation or the time, so that an element
+    /// which matches none may still be rewritten in a later generation
+    timed: bool,
+}
+
+impl<'a> Stream<'a> {
+    fn expand(
+        &self,
+        element: &Element<ActualParam>,
+        generation: usize,
+        index: usize,
+    ) -> Result<Option<LString>> {
+        let globals = self.lsystem.globals(generation, index);
+        for production in &self.lsystem.productions {
+            if production.matches(element, &globals)? {
+                return production.apply(element, &globals).map(Some);
             }
         }
-        None
+        Ok(None)
     }
 }
 
+impl<'a> Iterator for Stream<'a> {
+    type Item = Result<Element<ActualParam>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (elements, depth) = self.stack.last_mut()?;
+            let depth = *depth;
+            let element = match elements.next() {
+                Some(element) => element,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            let generation = self.n - depth;
+            let index = self.counters[generation];
+            self.counters[generation] += 1;
+            if depth == 0 {
+                return Some(Ok(element));
+            }
+            match self.expand(&element, generation, index) {
+                Ok(Some(lstring)) => self.stack.push((lstring.0.into_iter(), depth - 1)),
+                Ok(None) if self.timed => {
+                    self.stack.push((vec![element].into_iter(), depth - 1));
+                }
+                Ok(None) => {
+                    // The element stays the same in every later generation
+                    for counter in &mut self.counters[generation + 1..] {
+                        *counter += 1;
+                    }
+                    return Some(Ok(element));
+                }
+                Err(e) => {
+                    self.stack.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Yields the generations in turn, ending after a fixed point is reached
 impl Iterator for LSystem {
-    type Item = LString;
+    type Item = Result<LString>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.count > 0 {
-            self.generate();
+            if self.is_fixed_point() {
+                return None;
+            }
+            if let Err(e) = self.generate() {
+                return Some(Err(e));
+            }
         }
         self.count += 1;
-        Some(self.current.clone())
+        Some(Ok(self.current.clone()))
     }
 
     fn nth(&mut self, n: usize) -> Option<Self::Item> {
         for _ in 0..n {
-            self.generate();
+            if self.is_fixed_point() {
+                break;
+            }
+            if let Err(e) = self.generate() {
+                return Some(Err(e));
+            }
         }
         self.next()
     }
@@ -113,8 +680,9 @@ impl Production {
     pub fn new() -> Production {
         Production {
             pred: Element::new(),
+            locals: Vec::new(),
             condition: None,
-            probability: 1.0,
+            probability: Expression::Value(1.0),
             succ: Vec::new(),
         }
     }
@@ -123,11 +691,16 @@ impl Production {
         self.pred = pred;
     }
 
+    /// Adds a variable computed from the parameters and earlier locals
+    pub fn add_local(&mut self, var: FormalParam, value: Expression) {
+        self.locals.push((var, value));
+    }
+
     pub fn set_condition(&mut self, condition: Expression) {
         self.condition = Some(condition);
     }
 
-    pub fn set_probability(&mut self, probability: f32) {
+    pub fn set_probability(&mut self, probability: Expression) {
         self.probability = probability;
     }
 
@@ -135,42 +708,122 @@ impl Production {
         self.succ.push(element);
     }
 
-    fn matches(&self, element: &Element<ActualParam>) -> bool {
-        self.pred.matches(element)
-            && match &self.condition {
-                None => true,
-                Some(expression) => expression.eval_bool(&self.context(element)),
-            }
+    pub fn predecessor(&self) -> &Element<FormalParam> {
+        &self.pred
+    }
+
+    pub fn locals(&self) -> &[(FormalParam, Expression)] {
+        &self.locals
     }
 
-    fn context(&self, element: &Element<ActualParam>) -> Context {
-        self.pred
-            .params
+    pub fn condition(&self) -> Option<&Expression> {
+        self.condition.as_ref()
+    }
+
+    pub fn probability(&self) -> &Expression {
+        &self.probability
+    }
+
+    /// True if whether or how often the production is chosen depends on the
+    /// generation or the time
+    fn is_timed(&self) -> bool {
+        self.condition
             .iter()
-            .cloned()
-            .zip(element.params.iter().cloned())
-            .collect()
+            .chain(self.locals.iter().map(|(_, value)| value))
+            .chain(std::iter::once(&self.probability))
+            .any(|expression| expression.reads(GENERATION) || expression.reads(TIME))
+    }
+
+    /// True if the production is always chosen when it matches
+    fn is_certain(&self) -> bool {
+        self.probability.constant() == Some(1.0)
+    }
+
+    /// The weight of the production for an element it matches, negative weights
+    /// counting as 0
+    fn weight(&self, element: &Element<ActualParam>, globals: &Context) -> Result<f32> {
+        Ok(self
+            .probability
+            .eval(&self.context(element, globals)?)?
+            .max(0.0))
+    }
+
+    pub fn successors(&self) -> &[Element<Expression>] {
+        &self.succ
+    }
+
+    fn matches(&self, element: &Element<ActualParam>, globals: &Context) -> Result<bool> {
+        if !self.pred.matches(element) {
+            return Ok(false);
+        }
+        match &self.condition {
+            None => Ok(true),
+            Some(expression) => expression.eval_bool(&self.context(element, globals)?),
+        }
     }
 
-    fn apply(&self, element: &Element<ActualParam>) -> LString {
+    /// Binds the formal parameters to the element's parameters, after the reserved
+    /// variables in `globals` and before the locals
+    fn context(&self, element: &Element<ActualParam>, globals: &Context) -> Result<Context> {
+        let mut context = globals.clone();
+        context.extend(
+            self.pred
+                .params
+                .iter()
+                .cloned()
+                .zip(element.params.iter().cloned()),
+        );
+        for (var, value) in &self.locals {
+            let value = value.eval(&context)?;
+            context.push((*var, value));
+        }
+        Ok(context)
+    }
+
+    pub(crate) fn apply(
+        &self,
+        element: &Element<ActualParam>,
+        globals: &Context,
+    ) -> Result<LString> {
+        let context = self.context(element, globals)?;
         self.succ
             .iter()
-            .map(|Element { symbol, params }| Element {
-                symbol: *symbol,
-                params: params
-                    .into_iter()
-                    .map(|param| param.eval(&self.context(element)))
-                    .collect(),
+            .map(|Element { symbol, params }| {
+                Ok(Element {
+                    symbol: *symbol,
+                    params: params
+                        .iter()
+                        .map(|param| param.eval(&context))
+                        .collect::<Result<_>>()?,
+                })
             })
             .collect()
     }
 }
 
+impl Default for Production {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl LString {
     pub fn new() -> Self {
         LString(Vec::new())
     }
 
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Element<ActualParam>> {
+        self.0.get(index)
+    }
+
     fn clear(&mut self) {
         self.0.clear();
     }
@@ -198,7 +851,7 @@ impl FromIterator<Element<ActualParam>> for LString {
     where
         I: IntoIterator<Item = Element<ActualParam>>,
     {
-        LString(Vec::from_iter(iter.into_iter()))
+        LString(Vec::from_iter(iter))
     }
 }
 
@@ -213,6 +866,14 @@ impl fmt::Display for LSystem {
     }
 }
 
+impl FromStr for LString {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parser::parse_lstring(s)
+    }
+}
+
 impl fmt::Display for LString {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for element in self {
@@ -224,8 +885,8 @@ impl fmt::Display for LString {
 
 impl<T: fmt::Display> fmt::Display for Element<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.symbol)?;
-        if self.params.len() > 0 {
+        write!(f, "{}", symbol::label(self.symbol))?;
+        if !self.params.is_empty() {
             write!(f, "(")?;
             let mut iter = self.params.iter();
             write!(f, "{}", iter.next().unwrap())?;
@@ -240,10 +901,228 @@ impl<T: fmt::Display> fmt::Display for Element<T> {
 
 impl fmt::Display for Production {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}=", self.pred)?;
+        if !self.is_certain() {
+            write!(f, "{{{}}}", self.probability)?;
+        }
+        write!(f, "{}", self.pred)?;
+        if !self.locals.is_empty() || self.condition.is_some() {
+            write!(f, ":")?;
+        }
+        if !self.locals.is_empty() {
+            let locals: Vec<String> = self
+                .locals
+                .iter()
+                .map(|(var, value)| format!("{}={}", var, value))
+                .collect();
+            write!(f, "{{{}}}", locals.join(";"))?;
+        }
+        if let Some(condition) = &self.condition {
+            write!(f, "{}", condition)?;
+        }
+        write!(f, "=")?;
         for element in &self.succ {
             write!(f, "{}", element)?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_lsys;
+
+    #[test]
+    fn goto_replays_stochastic_generations() {
+        let mut lsystem = parse_lsys("F\n{0.5} F=F[+F]\n{0.5} F=FF").unwrap();
+        lsystem.goto(4).unwrap();
+        let fourth = lsystem.current.to_string();
+        lsystem.goto(6).unwrap();
+        lsystem.goto(4).unwrap();
+        assert_eq!(lsystem.current_generation(), 4);
+        assert_eq!(lsystem.current.to_string(), fourth);
+    }
+
+    #[test]
+    fn reseed_is_repeatable() {
+        let mut lsystem = parse_lsys("F\n{0.5} F=F[+F]\n{0.5} F=FF").unwrap();
+        lsystem.goto(5).unwrap();
+        lsystem.reseed(7).unwrap();
+        let seven = lsystem.current.to_string();
+        lsystem.reseed(8).unwrap();
+        lsystem.reseed(7).unwrap();
+        assert_eq!(lsystem.current_generation(), 5);
+        assert_eq!(lsystem.current.to_string(), seven);
+    }
+
+    #[test]
+    fn neighbouring_seeds_differ() {
+        let mut lsystem = parse_lsys("F").unwrap();
+        lsystem.reseed(1).unwrap();
+        let shifted = lsystem.rng(1).gen::<u64>();
+        lsystem.reseed(2).unwrap();
+        assert_ne!(lsystem.rng(0).gen::<u64>(), shifted);
+    }
+
+    #[test]
+    fn step_back_through_history() {
+        let mut lsystem = parse_lsys("F\n{0.5} F=F[+F]\n{0.5} F=FF").unwrap();
+        lsystem.set_history_limit(2);
+        let generations: Vec<String> = (0..6)
+            .map(|_| lsystem.next().unwrap().unwrap().to_string())
+            .collect();
+        for k in (0..5).rev() {
+            assert!(lsystem.step_back().unwrap());
+            assert_eq!(lsystem.current_generation(), k);
+            assert_eq!(lsystem.current.to_string(), generations[k]);
+        }
+        assert!(!lsystem.step_back().unwrap());
+    }
+
+    #[test]
+    fn random_access_to_generations() {
+        let mut lsystem = parse_lsys("F\n{0.5} F=F[+F]\n{0.5} F=FF").unwrap();
+        lsystem.set_history_limit(1);
+        lsystem.goto(3).unwrap();
+        let third = lsystem.current.to_string();
+        lsystem.goto(5).unwrap();
+        let fifth = lsystem.current.to_string();
+        assert_eq!(lsystem.generation(3).unwrap().to_string(), third);
+        assert_eq!(lsystem.generation(5).unwrap().to_string(), fifth);
+        assert_eq!(lsystem.current_generation(), 5);
+        lsystem.goto(3).unwrap();
+        assert_eq!(lsystem.generation(5).unwrap().to_string(), fifth);
+    }
+
+    #[test]
+    fn normalize_probabilities() {
+        let mut lsystem = parse_lsys("F\n{0.3} F=A\n{0.3} F=B").unwrap();
+        lsystem.goto(1).unwrap();
+        let normalized: Vec<String> = (0..50)
+            .map(|seed| {
+                lsystem.reseed(seed).unwrap();
+                lsystem.current.to_string()
+            })
+            .collect();
+        assert!(normalized.iter().all(|s| s == "A" || s == "B"));
+        assert!(normalized.iter().any(|s| s == "A"));
+        assert!(normalized.iter().any(|s| s == "B"));
+
+        lsystem.set_selection(Selection::Legacy).unwrap();
+        let unchanged = (0..50)
+            .filter(|&seed| {
+                lsystem.reseed(seed).unwrap();
+                lsystem.current.to_string() == "F"
+            })
+            .count();
+        assert!(unchanged > 0);
+    }
+
+    #[test]
+    fn unfired_production_is_not_a_fixed_point() {
+        let mut lsystem = parse_lsys("A\n{0.2} A=B").unwrap();
+        lsystem.set_selection(Selection::Legacy).unwrap();
+        let mut unfired = 0;
+        for seed in 0..20 {
+            lsystem.reseed(seed).unwrap();
+            lsystem.goto(1).unwrap();
+            if lsystem.current.to_string() == "A" {
+                assert!(!lsystem.is_fixed_point());
+                assert!(!lsystem.stats().unwrap().fixed_point);
+                unfired += 1;
+            }
+        }
+        assert!(unfired > 0);
+        lsystem.reset();
+        lsystem.nth(40).unwrap().unwrap();
+        assert_eq!(lsystem.current.to_string(), "B");
+    }
+
+    #[test]
+    fn parameter_dependent_probabilities() {
+        for seed in 0..20 {
+            let mut lsystem = parse_lsys("F(1)F(0)\n{x} F(x)=A\n{1-x} F(x)=B").unwrap();
+            lsystem.reseed(seed).unwrap();
+            lsystem.generate().unwrap();
+            assert_eq!(lsystem.current.to_string(), "AB");
+        }
+    }
+
+    #[test]
+    fn first_deterministic_production_wins() {
+        let mut lsystem = parse_lsys("A(0)\nA(x):x<3=A(x+1)\nA(x)=B").unwrap();
+        lsystem.goto(1).unwrap();
+        for seed in 0..20 {
+            lsystem.reseed(seed).unwrap();
+            assert_eq!(lsystem.current.to_string(), "A(1)");
+        }
+    }
+
+    #[test]
+    fn validate_probabilities() {
+        let lsystem =
+            parse_lsys("F\n{0.3} F=A\n{0.3} F=B\nA=B\n{0.5} B(x)=A\n{0.5} B(x)=B").unwrap();
+        assert_eq!(lsystem.validate().len(), 1);
+        let lsystem = parse_lsys("F(1)\n{0.5} F(x):x>0=A\n{0.5} F(x)=B").unwrap();
+        assert_eq!(lsystem.validate().len(), 1);
+        let lsystem = parse_lsys("F\nF=FF\nB=A").unwrap();
+        assert!(lsystem.validate().is_empty());
+        let lsystem = parse_lsys("F\nF:gen>2=F\nF=FF\nF=F").unwrap();
+        assert_eq!(lsystem.validate().len(), 1);
+    }
+
+    #[test]
+    fn reserved_variables() {
+        let mut lsystem = parse_lsys("AAA\nA:gen>1=B(index)\nA=A\nB(x)=B(x+time)").unwrap();
+        lsystem.set_time_step(0.5).unwrap();
+        lsystem.goto(4).unwrap();
+        assert_eq!(lsystem.current.to_string(), "B(1.5)B(2.5)B(3.5)");
+        let streamed = lsystem
+            .stream(4)
+            .unwrap()
+            .map(|element| element.unwrap().to_string())
+            .collect::<String>();
+        assert_eq!(streamed, lsystem.current.to_string());
+    }
+
+    #[test]
+    fn timed_production_is_not_a_fixed_point() {
+        let mut lsystem = parse_lsys("A\nA:gen>5=B").unwrap();
+        assert_eq!(lsystem.nth(8).unwrap().unwrap().to_string(), "B");
+        assert!(!lsystem.stats().unwrap().fixed_point);
+        let mut lsystem = parse_lsys("A\n{time} A=B").unwrap();
+        lsystem.goto(1).unwrap();
+        assert!(!lsystem.is_fixed_point());
+    }
+
+    #[test]
+    fn stream_timed_conditions() {
+        for source in &["A\nA:gen>2=B", "AA\nA:index>0=B\nB:time>1=C"] {
+            let mut lsystem = parse_lsys(source).unwrap();
+            lsystem.goto(5).unwrap();
+            let streamed = lsystem
+                .stream(5)
+                .unwrap()
+                .map(|element| element.unwrap().to_string())
+                .collect::<String>();
+            assert_eq!(streamed, lsystem.current.to_string());
+        }
+    }
+
+    #[test]
+    fn stream_matches_generation() {
+        let lsystem = parse_lsys("A(0)B\nA(x):x<3=A(x+1)[B]A(x+2)\nB=BC").unwrap();
+        let streamed = lsystem
+            .stream(5)
+            .unwrap()
+            .map(|element| element.unwrap().to_string())
+            .collect::<String>();
+        assert_eq!(streamed, lsystem.generation(5).unwrap().to_string());
+    }
+
+    #[test]
+    fn stream_rejects_stochastic_productions() {
+        let lsystem = parse_lsys("F\n{0.5} F=F[+F]\n{0.5} F=FF").unwrap();
+        assert!(lsystem.stream(3).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! Colours chosen by index with the turtle command `'`.
+//!
+//! The turtle uses the palette named by the setting `turtle.palette`, or else the
+//! colours of `turtle.colors`. A palette `name` is defined by either of
+//!
+//! ```text
+//! palette.name.colors=[[r, g, b], ...]
+//! palette.name.file="name.map"
+//! ```
+//!
+//! where a colormap file has one `r g b` colour per line, from 0 to 1, and `#` starts
+//! a comment. With `palette.name.size=n` the colours are taken as the stops of a
+//! gradient, spread evenly over `n` entries. An index between two entries blends
+//! them, so an expression such as `'(x/4)` shades smoothly along a palette.
+extern crate nalgebra_glm as glm;
+use crate::config::find_config;
+use crate::error::{Error, Result};
+use config::ConfigError;
+use glm::Vec3;
+use std::fs;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    colors: Vec<Vec3>,
+}
+
+impl Palette {
+    pub fn new(colors: Vec<Vec3>) -> Result<Self> {
+        if colors.is_empty() {
+            return Err(Error::Config(ConfigError::Message(
+                "a palette needs at least one colour".to_string(),
+            )));
+        }
+        Ok(Palette { colors })
+    }
+
+    /// The turtle's palette, as configured, falling back to the single `default` colour
+    pub fn from_config(default: Vec3) -> Result<Self> {
+        if let Some(name) = find_config::<String>("turtle.palette")? {
+            return Palette::named(&name);
+        }
+        match find_config::<Vec<[f32; 3]>>("turtle.colors")? {
+            Some(colors) => Palette::new(colors.into_iter().map(Vec3::from).collect()),
+            None => Palette::new(vec![default]),
+        }
+    }
+
+    /// The palette defined by the `palette.<name>` settings
+    pub fn named(name: &str) -> Result<Self> {
+        let key = |property: &str| format!("palette.{}.{}", name, property);
+        let palette = if let Some(colors) = find_config::<Vec<[f32; 3]>>(&key("colors"))? {
+            Palette::new(colors.into_iter().map(Vec3::from).collect())?
+        } else if let Some(path) = find_config::<String>(&key("file"))? {
+            fs::read_to_string(&path)?.parse()?
+        } else {
+            return Err(Error::Config(ConfigError::Message(format!(
+                "palette '{}' is not defined, set '{}' or '{}'",
+                name,
+                key("colors"),
+                key("file")
+            ))));
+        };
+        match find_config::<usize>(&key("size"))? {
+            Some(size) => palette.gradient(size),
+            None => Ok(palette),
+        }
+    }
+
+    /// `size` entries spread evenly from the first colour to the last
+    pub fn gradient(&self, size: usize) -> Result<Self> {
+        let last = self.colors.len() - 1;
+        let colors = (0..size)
+            .map(|i| match size {
+                1 => self.colors[0],
+                _ => self.color(i as f32 * last as f32 / (size - 1) as f32),
+            })
+            .collect();
+        Palette::new(colors)
+    }
+
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+
+    /// The colour at `index`, blending the entries either side of a fractional index.
+    /// Indices before the first entry or past the last take that entry.
+    pub fn color(&self, index: f32) -> Vec3 {
+        let last = self.colors.len() - 1;
+        let index = index.max(0.0).min(last as f32);
+        let i = index.floor() as usize;
+        match self.colors.get(i + 1) {
+            Some(next) => glm::lerp(&self.colors[i], next, index - i as f32),
+            None => self.colors[i],
+        }
+    }
+}
+
+impl std::str::FromStr for Palette {
+    type Err = Error;
+
+    /// Reads a colormap, one `r g b` colour per line
+    fn from_str(s: &str) -> Result<Self> {
+        let mut colors = Vec::new();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let values = line
+                .split_whitespace()
+                .map(|word| {
+                    word.parse::<f32>().map_err(|e| Error::Token {
+                        token: format!("{} (line {})", word, n + 1),
+                        source: Box::new(e),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            match values[..] {
+                [] => (),
+                [r, g, b] => colors.push(Vec3::new(r, g, b)),
+                _ => {
+                    return Err(Error::Format {
+                        line: n + 1,
+                        message: "expected a colour 'r g b'".to_string(),
+                    })
+                }
+            }
+        }
+        Palette::new(colors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_setting;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn interpolate_colors() {
+        let palette: Palette = "# red to blue\n1 0 0\n\n0 0 1\n".parse().unwrap();
+        assert_eq!(palette.len(), 2);
+        assert_eq!(palette.color(0.0), Vec3::x());
+        assert_relative_eq!(palette.color(0.25), Vec3::new(0.75, 0.0, 0.25));
+        assert_eq!(palette.color(7.0), Vec3::z());
+        assert_eq!(palette.color(-1.0), Vec3::x());
+        assert!("1 0\n".parse::<Palette>().is_err());
+        assert!("".parse::<Palette>().is_err());
+    }
+
+    #[test]
+    fn gradient() {
+        let palette = Palette::new(vec![Vec3::zeros(), Vec3::x(), Vec3::new(1.0, 1.0, 0.0)])
+            .unwrap()
+            .gradient(5)
+            .unwrap();
+        assert_eq!(palette.len(), 5);
+        assert_relative_eq!(palette.color(1.0), Vec3::new(0.5, 0.0, 0.0));
+        assert_relative_eq!(palette.color(2.0), Vec3::x());
+        assert_relative_eq!(palette.color(4.0), Vec3::new(1.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn named_palette() {
+        let path = std::env::temp_dir().join("abop_test_palette.map");
+        fs::write(&path, "0 0 0\n1 1 1\n").unwrap();
+        parse_setting(&format!("palette.testmap.file=\"{}\"", path.display())).unwrap();
+        parse_setting("palette.testmap.size=3").unwrap();
+        let palette = Palette::named("testmap").unwrap();
+        assert_eq!(palette.len(), 3);
+        assert_relative_eq!(palette.color(1.0), Vec3::new(0.5, 0.5, 0.5));
+        match Palette::named("nosuchpalette") {
+            Err(Error::Config(_)) => (),
+            other => panic!("expected configuration error, got {:?}", other),
+        }
+        parse_setting("palette.mistyped.colors=\"red\"").unwrap();
+        let error = Palette::named("mistyped").unwrap_err().to_string();
+        assert!(!error.contains("not defined"), "{}", error);
+    }
+}
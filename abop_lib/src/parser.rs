@@ -1,84 +1,190 @@
-use crate::config::set_config;
-use crate::lsys::{Element, LString, LSystem, Production};
+use crate::config::{find_config, set_config};
+use crate::error::{Error, Result};
+use crate::lsys::{Element, LString, LSystem, Production, Symbol};
+use crate::symbol;
 use pest::iterators::Pair;
 use pest::Parser;
 use pest_derive::Parser;
+use std::error;
 use std::str::FromStr;
 
 #[derive(Parser)]
 #[grammar = "lsys.pest"]
 pub struct LSystemParser;
 
-pub fn parse_lsys(s: &str) -> LSystem {
+pub fn parse_lsys(s: &str) -> Result<LSystem> {
     let mut axiom: LString = LString::new();
     let mut productions: Vec<Production> = Vec::new();
-    LSystemParser::parse(Rule::lsystem, s)
-        .expect("Unsuccessful parse")
-        .next()
-        .unwrap()
-        .into_inner()
-        .for_each(|r| match r.as_rule() {
-            Rule::setting => produce_setting(r),
-            Rule::axiom => axiom = produce_axiom(r),
-            Rule::production => productions.push(produce_production(r)),
+    let lsystem = LSystemParser::parse(Rule::lsystem, s)?.next().unwrap();
+    for r in lsystem.into_inner() {
+        match r.as_rule() {
+            Rule::setting => produce_setting(r)?,
+            Rule::axiom => axiom = produce_axiom(r)?,
+            Rule::production => productions.push(produce_production(r)?),
             _ => (),
-        });
+        }
+    }
+
+    let mut lsystem = LSystem::new(axiom, productions);
+    if let Some(time_step) = find_config("lsystem.timestep")? {
+        lsystem.set_time_step(time_step)?;
+    }
+    Ok(lsystem)
+}
+
+/// Parses an lstring in the format written by its `Display` implementation,
+/// for example `F(1, 0.5)+[-F]`. Line breaks are ignored.
+pub fn parse_lstring(s: &str) -> Result<LString> {
+    let lstring = LSystemParser::parse(Rule::lstring, s)?.next().unwrap();
+    lstring
+        .into_inner()
+        .filter(|r| r.as_rule() == Rule::axiom_element)
+        .map(produce_element)
+        .collect()
+}
 
-    LSystem::new(axiom, productions)
+/// Applies a setting written as `key=value`, as it would appear after `#set`
+/// in an lsystem file.
+pub fn parse_setting(s: &str) -> Result<()> {
+    produce_setting(LSystemParser::parse(Rule::assignment, s)?.next().unwrap())
 }
 
-fn produce_setting(setting: Pair<Rule>) {
+fn produce_setting(setting: Pair<Rule>) -> Result<()> {
     let mut setting = setting.into_inner();
     let name = setting.next().unwrap().as_str();
     let value = setting.next().unwrap().into_inner().next().unwrap();
     match value.as_rule() {
-        Rule::number => set_config(name, from_str::<f64>(value)),
+        Rule::number => set_config(name, from_str::<f64>(value)?),
+        Rule::string => set_config(name, value.into_inner().next().unwrap().as_str()),
         Rule::array => set_config(
             name,
             value
                 .into_inner()
                 .map(from_str::<f64>)
-                .collect::<Vec<f64>>(),
+                .collect::<Result<Vec<f64>>>()?,
         ),
         _ => unreachable!(),
-    };
+    }
 }
 
-fn produce_axiom(axiom: Pair<Rule>) -> LString {
+fn produce_axiom(axiom: Pair<Rule>) -> Result<LString> {
     axiom.into_inner().map(produce_element).collect()
 }
 
-fn produce_production(production: Pair<Rule>) -> Production {
-    let production = production.into_inner();
+fn produce_production(production: Pair<Rule>) -> Result<Production> {
     let mut result = Production::new();
-    production.for_each(|r| match r.as_rule() {
-        Rule::pred => result.set_predecessor(produce_element(r)),
-        Rule::probability => result.set_probability(from_str(r)),
-        Rule::condition => result.set_condition(from_str(r)),
-        Rule::succ => result.add_successor(produce_element(r)),
-        _ => unreachable!(),
-    });
-    result
+    for r in production.into_inner() {
+        match r.as_rule() {
+            Rule::pred => result.set_predecessor(produce_element(r)?),
+            Rule::probability => result.set_probability(from_str(r)?),
+            Rule::locals => {
+                for local in r.into_inner() {
+                    let mut local = local.into_inner();
+                    let var = from_str(local.next().unwrap())?;
+                    result.add_local(var, from_str(local.next().unwrap())?);
+                }
+            }
+            Rule::condition => result.set_condition(from_str(r)?),
+            Rule::succ => result.add_successor(produce_element(r)?),
+            _ => unreachable!(),
+        }
+    }
+    Ok(result)
 }
 
-fn produce_element<T>(element: Pair<Rule>) -> Element<T>
+fn produce_element<T>(element: Pair<Rule>) -> Result<Element<T>>
 where
     T: FromStr + Clone + Default,
+    T::Err: Into<Box<dyn error::Error + Send + Sync>>,
 {
     let mut element = element.into_inner();
-    let symbol = from_str(element.next().unwrap());
+    let symbol = produce_symbol(element.next().unwrap())?;
     match element.next() {
-        Some(params) => Element {
+        Some(params) => Ok(Element {
             symbol,
-            params: params.into_inner().map(from_str).collect(),
-        },
-        None => Element {
+            params: params.into_inner().map(from_str).collect::<Result<_>>()?,
+        }),
+        None => Ok(Element {
             symbol,
             params: Vec::new(),
-        },
+        }),
     }
 }
 
-fn from_str<T: FromStr>(rule: Pair<Rule>) -> T {
-    rule.as_str().parse().ok().unwrap()
+fn produce_symbol(symbol: Pair<Rule>) -> Result<Symbol> {
+    match symbol.clone().into_inner().next() {
+        Some(named) => symbol::intern(named.as_str()),
+        None => from_str(symbol),
+    }
+}
+
+fn from_str<T>(rule: Pair<Rule>) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Into<Box<dyn error::Error + Send + Sync>>,
+{
+    rule.as_str().parse().map_err(|e: T::Err| Error::Token {
+        token: rule.as_str().to_string(),
+        source: e.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_syntax_error() {
+        match parse_lsys("F\nF=F(") {
+            Err(Error::Syntax(_)) => (),
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn generate_undefined_variable() {
+        let mut lsystem = parse_lsys("F(1)\nF(x)=F(y)").unwrap();
+        match lsystem.generate() {
+            Err(Error::UndefinedVariable('y')) => (),
+            other => panic!("expected undefined variable, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lstring_round_trip() {
+        let mut lsystem = parse_lsys("F(0.01)\nF(x)=F(x*2)+(45)[F(x/3)]-(60)").unwrap();
+        lsystem.generate().unwrap();
+        lsystem.generate().unwrap();
+        let text = lsystem.current.to_string();
+        assert_eq!(parse_lstring(&text).unwrap().to_string(), text);
+        assert_eq!(
+            parse_lstring(&format!("{}\n", text)).unwrap().to_string(),
+            text
+        );
+    }
+
+    #[test]
+    fn surface_followed_by_module() {
+        let lstring = parse_lstring("~lF~p(2)").unwrap();
+        assert_eq!(lstring.len(), 3);
+        assert_eq!(lstring.get(1).unwrap().symbol, 'F');
+        assert_eq!(lstring.to_string(), "~lF~p(2)");
+    }
+
+    #[test]
+    fn local_variables() {
+        let mut lsystem = parse_lsys("A(2)A(1)\nA(x):{y=x*0.5; z=y^2} x>1=B(y)C(z)").unwrap();
+        lsystem.generate().unwrap();
+        assert_eq!(lsystem.current.to_string(), "B(1)C(1)A(1)");
+    }
+
+    #[test]
+    fn production_round_trip() {
+        let source = "F(1,2)\n{(x+1)/2}F(x, y):{z=(x-y)*2;w=x^y^2}x-(y-1)>0&&z<3=F(z, w)+(x-(y+z))";
+        let lsystem = parse_lsys(source).unwrap();
+        let text = lsystem.productions()[0].to_string();
+        assert_eq!(text, source.lines().nth(1).unwrap());
+        let reparsed = parse_lsys(&format!("F(1,2)\n{}", text)).unwrap();
+        assert_eq!(reparsed.productions()[0].to_string(), text);
+    }
 }
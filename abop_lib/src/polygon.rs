@@ -0,0 +1,184 @@
+extern crate nalgebra_glm as glm;
+use glm::{Vec2, Vec3};
+
+/// The normal of a polygon by Newell's method, which is robust to concave and
+/// slightly non-planar polygons. It points the way from which the vertices are seen
+/// counterclockwise, and is `None` for polygons without area.
+pub fn normal(points: &[Vec3]) -> Option<Vec3> {
+    let n = points.len();
+    let mut normal = Vec3::zeros();
+    for i in 0..n {
+        let p = points[i];
+        let q = points[(i + 1) % n];
+        normal.x += (p.y - q.y) * (p.z + q.z);
+        normal.y += (p.z - q.z) * (p.x + q.x);
+        normal.z += (p.x - q.x) * (p.y + q.y);
+    }
+    let length = glm::length(&normal);
+    if length > f32::EPSILON * scale(points) {
+        Some(normal / length)
+    } else {
+        None
+    }
+}
+
+/// Splits a simple polygon into triangles by ear clipping, returning the indices of
+/// the vertices of each triangle, wound the same way as the polygon.
+///
+/// The polygon is projected onto the plane perpendicular to its normal first, so
+/// near-planar polygons are triangulated as their projection. Collinear vertices
+/// are dropped without making empty triangles, and a self-intersecting polygon is
+/// still covered, if not exactly.
+pub fn triangulate(points: &[Vec3]) -> Vec<[usize; 3]> {
+    let normal = match normal(points) {
+        Some(normal) => normal,
+        None => return Vec::new(),
+    };
+    let projected = project(points, &normal);
+    let epsilon = f32::EPSILON * scale(points) * scale(points);
+
+    let mut remaining: Vec<usize> = (0..points.len()).collect();
+    let mut triangles = Vec::with_capacity(points.len().saturating_sub(2));
+    while remaining.len() > 2 {
+        let n = remaining.len();
+        let corner = |i: usize| {
+            let (a, b, c) = (
+                remaining[(i + n - 1) % n],
+                remaining[i],
+                remaining[(i + 1) % n],
+            );
+            (a, b, c, cross(&projected[a], &projected[b], &projected[c]))
+        };
+        let collinear = (0..n).find(|&i| corner(i).3.abs() <= epsilon);
+        let ear = collinear.or_else(|| {
+            (0..n).find(|&i| {
+                let (a, b, c, area) = corner(i);
+                area > 0.0
+                    && remaining.iter().all(|&p| {
+                        p == a
+                            || p == b
+                            || p == c
+                            || !inside(&projected[p], &projected[a], &projected[b], &projected[c])
+                    })
+            })
+        });
+        // Without an ear the polygon is not simple, so clip the most convex corner
+        let i = ear.unwrap_or_else(|| {
+            (0..n)
+                .max_by(|&i, &j| corner(i).3.total_cmp(&corner(j).3))
+                .unwrap()
+        });
+        let (a, b, c, area) = corner(i);
+        if area.abs() > epsilon {
+            triangles.push([a, b, c]);
+        }
+        remaining.remove(i);
+    }
+    triangles
+}
+
+/// The largest coordinate of the points, to scale tolerances by
+fn scale(points: &[Vec3]) -> f32 {
+    points
+        .iter()
+        .map(|p| glm::comp_max(&glm::abs(p)))
+        .fold(1.0, f32::max)
+}
+
+/// The points in a right-handed basis of the plane perpendicular to `normal`
+fn project(points: &[Vec3], normal: &Vec3) -> Vec<Vec2> {
+    let axis = if normal.x.abs() < 0.9 {
+        Vec3::x()
+    } else {
+        Vec3::y()
+    };
+    let u = glm::normalize(&axis.cross(normal));
+    let v = normal.cross(&u);
+    points
+        .iter()
+        .map(|p| Vec2::new(glm::dot(p, &u), glm::dot(p, &v)))
+        .collect()
+}
+
+/// Twice the signed area of the triangle, positive when it turns counterclockwise
+fn cross(a: &Vec2, b: &Vec2, c: &Vec2) -> f32 {
+    (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+}
+
+fn inside(p: &Vec2, a: &Vec2, b: &Vec2, c: &Vec2) -> bool {
+    cross(a, b, p) >= 0.0 && cross(b, c, p) >= 0.0 && cross(c, a, p) >= 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    fn area(points: &[Vec3], triangles: &[[usize; 3]]) -> f32 {
+        triangles
+            .iter()
+            .map(|&[a, b, c]| {
+                glm::length(&(points[b] - points[a]).cross(&(points[c] - points[a]))) / 2.0
+            })
+            .sum()
+    }
+
+    #[test]
+    fn concave_polygon() {
+        // An L shape in the xz plane, seen counterclockwise from below
+        let points = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(2.0, 0.0, 1.0),
+            Vec3::new(1.0, 0.0, 1.0),
+            Vec3::new(1.0, 0.0, 2.0),
+            Vec3::new(0.0, 0.0, 2.0),
+        ];
+        assert_relative_eq!(normal(&points).unwrap(), Vec3::new(0.0, -1.0, 0.0));
+        let triangles = triangulate(&points);
+        assert_eq!(triangles.len(), 4);
+        assert_relative_eq!(area(&points, &triangles), 3.0, epsilon = 1e-5);
+        for &[a, b, c] in &triangles {
+            let n = (points[b] - points[a]).cross(&(points[c] - points[a]));
+            assert!(n.y < 0.0);
+        }
+    }
+
+    #[test]
+    fn near_planar_polygon() {
+        let points = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.01),
+            Vec3::new(1.0, 1.0, 0.0),
+            Vec3::new(0.5, 0.6, 0.0),
+            Vec3::new(0.0, 1.0, -0.01),
+        ];
+        let n = normal(&points).unwrap();
+        assert!(n.z > 0.99);
+        let triangles = triangulate(&points);
+        assert_eq!(triangles.len(), 3);
+        assert_relative_eq!(area(&points, &triangles), 0.8, epsilon = 1e-3);
+    }
+
+    #[test]
+    fn degenerate_polygons() {
+        let line = [Vec3::zeros(), Vec3::x(), Vec3::x() * 2.0];
+        assert!(normal(&line).is_none());
+        assert!(triangulate(&line).is_empty());
+        let collinear = [
+            Vec3::zeros(),
+            Vec3::x(),
+            Vec3::x() * 2.0,
+            Vec3::new(2.0, 1.0, 0.0),
+        ];
+        assert_eq!(triangulate(&collinear).len(), 1);
+        // Coordinates this large overflow to infinities and NaN areas
+        let huge = [
+            Vec3::zeros(),
+            Vec3::x(),
+            Vec3::new(1.0, 1.0, 0.0),
+            Vec3::new(f32::MAX, 1.0, 0.0),
+        ];
+        triangulate(&huge);
+    }
+}
@@ -0,0 +1,115 @@
+/// Where a module of a generation came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Origin {
+    /// The index of the parent module in the previous generation
+    pub parent: usize,
+    /// The index of the production which rewrote the parent, or `None` if the parent
+    /// was copied unchanged
+    pub production: Option<usize>,
+}
+
+/// One module on the path from the axiom to a module of the current generation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Step {
+    pub generation: usize,
+    pub index: usize,
+    /// The production which produced this module from the module of the previous step
+    pub production: Option<usize>,
+}
+
+/// The origin of every module of every generation since the axiom, recorded by
+/// `LSystem::generate` when provenance tracking is on.
+#[derive(Clone, Debug, Default)]
+pub struct Provenance {
+    /// The origins of the modules of generation `g + 1` at index `g`
+    origins: Vec<Vec<Origin>>,
+}
+
+impl Provenance {
+    pub fn new() -> Self {
+        Provenance {
+            origins: Vec::new(),
+        }
+    }
+
+    /// The origin of module `index` of `generation`, which is `None` for the axiom
+    pub fn origin(&self, generation: usize, index: usize) -> Option<Origin> {
+        match generation {
+            0 => None,
+            g => self.origins.get(g - 1)?.get(index).cloned(),
+        }
+    }
+
+    /// The path of modules from the axiom to module `index` of the latest generation,
+    /// or `None` if there is no such module.
+    pub fn trace(&self, mut index: usize) -> Option<Vec<Step>> {
+        let mut steps = Vec::new();
+        for (g, origins) in self.origins.iter().enumerate().rev() {
+            let origin = origins.get(index)?;
+            steps.push(Step {
+                generation: g + 1,
+                index,
+                production: origin.production,
+            });
+            index = origin.parent;
+        }
+        steps.push(Step {
+            generation: 0,
+            index,
+            production: None,
+        });
+        steps.reverse();
+        Some(steps)
+    }
+
+    pub(crate) fn push(&mut self, origins: Vec<Origin>) {
+        self.origins.push(origins);
+    }
+
+    /// Forgets the generations after `generation`
+    pub(crate) fn truncate(&mut self, generation: usize) {
+        self.origins.truncate(generation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_lsys;
+
+    #[test]
+    fn trace_module() {
+        let mut lsystem = parse_lsys("AB\nA=BA\nB=C").unwrap();
+        lsystem.set_provenance(true).unwrap();
+        lsystem.goto(2).unwrap();
+        assert_eq!(lsystem.current.to_string(), "CBAC");
+        let provenance = lsystem.provenance().unwrap();
+        assert_eq!(
+            provenance.origin(2, 0),
+            Some(Origin {
+                parent: 0,
+                production: Some(1)
+            })
+        );
+        let trace = provenance.trace(2).unwrap();
+        let path: Vec<_> = trace
+            .iter()
+            .map(|step| (step.generation, step.index, step.production))
+            .collect();
+        assert_eq!(path, vec![(0, 0, None), (1, 1, Some(0)), (2, 2, Some(0))]);
+        assert_eq!(provenance.origin(2, 3).unwrap().production, None);
+        assert!(provenance.trace(4).is_none());
+    }
+
+    #[test]
+    fn follow_history() {
+        let mut lsystem = parse_lsys("A\nA=AB\nB=A").unwrap();
+        lsystem.goto(4).unwrap();
+        lsystem.set_provenance(true).unwrap();
+        assert_eq!(lsystem.provenance().unwrap().trace(0).unwrap().len(), 5);
+        lsystem.step_back().unwrap();
+        assert_eq!(lsystem.provenance().unwrap().trace(0).unwrap().len(), 4);
+        lsystem.goto(1).unwrap();
+        assert!(lsystem.provenance().unwrap().trace(2).is_none());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::lsys::{LString, Symbol};
+use crate::symbol;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Statistics of one derivation step, describing generation `generation` and the
+/// rewriting which produced it from the previous generation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GenerationStats {
+    pub generation: usize,
+    /// The number of elements in the string
+    pub length: usize,
+    /// The number of elements of each symbol (the Parikh vector)
+    pub symbols: BTreeMap<Symbol, usize>,
+    /// The number of elements each production rewrote, in the order of the productions
+    pub rewrites: Vec<usize>,
+    /// No production fired, and none could fire on a later draw, so every later
+    /// generation is the same as this one
+    pub fixed_point: bool,
+}
+
+impl GenerationStats {
+    pub fn new(
+        generation: usize,
+        lstring: &LString,
+        rewrites: Vec<usize>,
+        fixed_point: bool,
+    ) -> Self {
+        let mut symbols = BTreeMap::new();
+        let mut length = 0;
+        for element in lstring {
+            *symbols.entry(element.symbol).or_insert(0) += 1;
+            length += 1;
+        }
+        GenerationStats {
+            generation,
+            length,
+            symbols,
+            fixed_point,
+            rewrites,
+        }
+    }
+
+    /// The column names of the table written by `Display`
+    pub const HEADER: &'static str =
+        "generation     length  fixed  rewrites per production / symbol counts";
+
+    /// Writes the statistics as a single line JSON object
+    pub fn to_json(&self) -> String {
+        let symbols: Vec<String> = self
+            .symbols
+            .iter()
+            .map(|(symbol, count)| format!("\"{}\":{}", escape(*symbol), count))
+            .collect();
+        let rewrites: Vec<String> = self.rewrites.iter().map(usize::to_string).collect();
+        format!(
+            "{{\"generation\":{},\"length\":{},\"fixed_point\":{},\"rewrites\":[{}],\"symbols\":{{{}}}}}",
+            self.generation,
+            self.length,
+            self.fixed_point,
+            rewrites.join(","),
+            symbols.join(",")
+        )
+    }
+}
+
+impl fmt::Display for GenerationStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>10} {:>10}  {:<5} ",
+            self.generation,
+            self.length,
+            if self.fixed_point { "yes" } else { "no" }
+        )?;
+        for count in &self.rewrites {
+            write!(f, " {}", count)?;
+        }
+        write!(f, " /")?;
+        for (symbol, count) in &self.symbols {
+            write!(f, " {}:{}", symbol::label(*symbol), count)?;
+        }
+        Ok(())
+    }
+}
+
+fn escape(symbol: Symbol) -> String {
+    match symbol {
+        '"' => "\\\"".to_string(),
+        '\\' => "\\\\".to_string(),
+        c => symbol::label(c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::parse_lsys;
+
+    #[test]
+    fn count_symbols_and_rewrites() {
+        let mut lsystem = parse_lsys("A\nA=AB\nB=A\nC=D").unwrap();
+        lsystem.goto(3).unwrap();
+        let stats = lsystem.stats().unwrap();
+        assert_eq!(lsystem.current.to_string(), "ABAAB");
+        assert_eq!(stats.length, 5);
+        assert_eq!(stats.symbols[&'A'], 3);
+        assert_eq!(stats.symbols[&'B'], 2);
+        assert_eq!(stats.rewrites, vec![2, 1, 0]);
+        assert!(!stats.fixed_point);
+        lsystem.step_back().unwrap();
+        assert_eq!(lsystem.stats().unwrap().rewrites, vec![1, 1, 0]);
+    }
+
+    #[test]
+    fn stop_at_fixed_point() {
+        let mut lsystem = parse_lsys("F(3)\nF(x):x>0=F(x-1)").unwrap();
+        assert_eq!(lsystem.by_ref().count(), 5);
+        assert_eq!(lsystem.current_generation(), 4);
+        assert!(lsystem.stats().unwrap().fixed_point);
+    }
+
+    #[test]
+    fn write_json() {
+        let mut lsystem = parse_lsys("\\\nF=\\F").unwrap();
+        lsystem.generate().unwrap();
+        assert_eq!(
+            lsystem.stats().unwrap().to_json(),
+            r#"{"generation":1,"length":1,"fixed_point":true,"rewrites":[0],"symbols":{"\\":1}}"#
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! Predefined surfaces, such as leaves and petals, which the turtle places with `~c`.
+//!
+//! As in cpfg, a surface is named by a single letter `c`, so that `~lF` is the surface
+//! `l` followed by `F`. The setting `surface.<c>` holds the path of its file, for
+//! example `#set surface.l="leaf.obj"`. Files ending in `.obj` are read as
+//! meshes (see `geometry::Mesh`); any other file is read as bicubic Bezier patches,
+//! sixteen control points per patch, one `x y z` point per line and row by row,
+//! with `#` starting a comment. The turtle draws a surface in its own frame, with x
+//! to the right, y along the heading and z up, so the surface's origin is where it
+//! is attached.
+extern crate nalgebra_glm as glm;
+use crate::config::find_config;
+use crate::error::{Error, Result};
+use crate::geometry::{Mesh, Vertex};
+use crate::lsys::Symbol;
+use crate::symbol;
+use config::ConfigError;
+use glm::Vec3;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// The number of quads along each side of a tessellated Bezier patch
+const PATCH_SEGMENTS: usize = 8;
+
+lazy_static! {
+    static ref SURFACES: RwLock<HashMap<PathBuf, Arc<Surface>>> = RwLock::new(HashMap::new());
+}
+
+/// The name of the surface `symbol` stands for, if it stands for one
+pub fn name(symbol: Symbol) -> Option<String> {
+    symbol::lookup(symbol)?
+        .strip_prefix('~')
+        .map(str::to_string)
+}
+
+/// The triangles of a surface in its own frame
+#[derive(Debug, Default)]
+pub struct Surface {
+    vertices: Vec<Vertex>,
+}
+
+impl Surface {
+    /// The surface set for `name`, read from its file the first time it is asked for
+    pub fn named(name: &str) -> Result<Arc<Surface>> {
+        let key = format!("surface.{}", name);
+        let path: String = find_config(&key)?.ok_or_else(|| {
+            Error::Config(ConfigError::Message(format!(
+                "surface '{}' is not defined, set '{}' to the path of its file",
+                name, key
+            )))
+        })?;
+        let path = PathBuf::from(path);
+        // Entries are only ever inserted whole, so the cache is still sound if a
+        // thread panicked while holding the lock
+        if let Some(surface) = SURFACES
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&path)
+        {
+            return Ok(surface.clone());
+        }
+        let surface = Arc::new(Surface::load(&path)?);
+        SURFACES
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(path, surface.clone());
+        Ok(surface)
+    }
+
+    pub fn load(path: &Path) -> Result<Surface> {
+        let text = fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("obj") => Ok(Surface {
+                vertices: text.parse::<Mesh>()?.unindexed(),
+            }),
+            _ => Surface::from_patches(&text),
+        }
+    }
+
+    /// Tessellates bicubic Bezier patches, each given by sixteen control points
+    pub fn from_patches(text: &str) -> Result<Surface> {
+        let mut points = Vec::new();
+        let mut last_line = 0;
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let values = line
+                .split_whitespace()
+                .map(|word| {
+                    word.parse::<f32>().map_err(|e| Error::Token {
+                        token: format!("{} (line {})", word, n + 1),
+                        source: Box::new(e),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            match values[..] {
+                [] => (),
+                [x, y, z] => points.push(Vec3::new(x, y, z)),
+                _ => {
+                    return Err(Error::Format {
+                        line: n + 1,
+                        message: "expected a control point 'x y z'".to_string(),
+                    })
+                }
+            }
+            last_line = n + 1;
+        }
+        if points.is_empty() || points.len() % 16 != 0 {
+            return Err(Error::Format {
+                line: last_line,
+                message: format!(
+                    "{} control points do not make whole patches of 16",
+                    points.len()
+                ),
+            });
+        }
+        let vertices = points
+            .chunks(16)
+            .flat_map(|patch| tessellate(patch, PATCH_SEGMENTS))
+            .collect();
+        Ok(Surface { vertices })
+    }
+
+    pub fn vertices(&self) -> &[Vertex] {
+        &self.vertices
+    }
+}
+
+fn tessellate(patch: &[Vec3], segments: usize) -> Vec<Vertex> {
+    let vertex = |i: usize, j: usize| {
+        let (u, v) = (i as f32 / segments as f32, j as f32 / segments as f32);
+        let (pos, norm) = evaluate(patch, u, v);
+        Vertex {
+            pos: [pos.x, pos.y, pos.z],
+            col: [1.0, 1.0, 1.0],
+            norm: [norm.x, norm.y, norm.z],
+        }
+    };
+    let mut vertices = Vec::with_capacity(segments * segments * 6);
+    for i in 0..segments {
+        for j in 0..segments {
+            let (a, b, c, d) = (
+                vertex(i, j),
+                vertex(i + 1, j),
+                vertex(i + 1, j + 1),
+                vertex(i, j + 1),
+            );
+            vertices.extend_from_slice(&[a, b, c, a, c, d]);
+        }
+    }
+    vertices
+}
+
+/// The point and normal of a patch at `(u, v)`. Where the patch is degenerate, as at
+/// a corner where control points meet, the normal is taken from just inside.
+fn evaluate(patch: &[Vec3], u: f32, v: f32) -> (Vec3, Vec3) {
+    let point = |u: f32, v: f32| {
+        let (bu, bv) = (bernstein(u), bernstein(v));
+        let mut p = Vec3::zeros();
+        for (i, row) in patch.chunks(4).enumerate() {
+            for (j, q) in row.iter().enumerate() {
+                p += q * (bv[i] * bu[j]);
+            }
+        }
+        p
+    };
+    let normal = |u: f32, v: f32| {
+        let h = 1e-3;
+        let du = point((u + h).min(1.0), v) - point((u - h).max(0.0), v);
+        let dv = point(u, (v + h).min(1.0)) - point(u, (v - h).max(0.0));
+        du.cross(&dv)
+    };
+    let mut n = normal(u, v);
+    if glm::length(&n) < 1e-12 {
+        n = normal(u.clamp(0.01, 0.99), v.clamp(0.01, 0.99));
+    }
+    let n = if glm::length(&n) > 0.0 {
+        glm::normalize(&n)
+    } else {
+        Vec3::z()
+    };
+    (point(u, v), n)
+}
+
+fn bernstein(t: f32) -> [f32; 4] {
+    let s = 1.0 - t;
+    [s * s * s, 3.0 * s * s * t, 3.0 * s * t * t, t * t * t]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn surface_names() {
+        let leaf = symbol::intern("~l").unwrap();
+        assert_eq!(name(leaf), Some("l".to_string()));
+        assert_eq!(name(symbol::ELASTICITY), None);
+        assert_eq!(name('F'), None);
+    }
+
+    #[test]
+    fn flat_patch() {
+        // A unit square in the xy plane, facing up the z axis
+        let text: String = (0..4)
+            .flat_map(|i| (0..4).map(move |j| format!("{} {} 0\n", j as f32 / 3.0, i as f32 / 3.0)))
+            .collect();
+        let surface = Surface::from_patches(&text).unwrap();
+        assert_eq!(
+            surface.vertices().len(),
+            PATCH_SEGMENTS * PATCH_SEGMENTS * 6
+        );
+        for v in surface.vertices() {
+            assert_relative_eq!(v.norm[2], 1.0, epsilon = 1e-4);
+        }
+        let area: f32 = surface
+            .vertices()
+            .chunks(3)
+            .map(|t| {
+                let [a, b, c] = [t[0].pos, t[1].pos, t[2].pos].map(Vec3::from);
+                (b - a).cross(&(c - a)).z / 2.0
+            })
+            .sum();
+        assert_relative_eq!(area, 1.0, epsilon = 1e-4);
+        assert!(Surface::from_patches("0 0 0\n").is_err());
+    }
+}
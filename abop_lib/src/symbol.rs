@@ -0,0 +1,105 @@
+//! Symbols written with more than one character, such as the turtle command `@T` or
+//! the surface `~l`. Each is given a character from the Unicode private use area,
+//! so that an element's symbol is still a single `char`: commands known to the turtle
+//! have fixed characters, and other names are given the next free one when first seen.
+use crate::error::{Error, Result};
+use crate::lsys::Symbol;
+use std::sync::{PoisonError, RwLock};
+
+/// `@T(e)` sets the elasticity with which segments bend towards the tropism vector
+pub const ELASTICITY: Symbol = '\u{E000}';
+
+/// `@O(r)` draws a sphere of radius `r`
+pub const SPHERE: Symbol = '\u{E001}';
+
+/// `@Gs` starts a generalized cylinder at the turtle
+pub const CYLINDER_START: Symbol = '\u{E002}';
+
+/// `@Gc(n)` sweeps the generalized cylinder to the turtle in `n` steps
+pub const CYLINDER_POINT: Symbol = '\u{E003}';
+
+/// `@Ge(n)` sweeps the generalized cylinder to the turtle and ends it
+pub const CYLINDER_END: Symbol = '\u{E004}';
+
+/// `@#(i)` changes the cross-section of branches to contour `i`
+pub const CONTOUR: Symbol = '\u{E005}';
+
+/// The turtle commands, which have fixed characters from U+E000
+const COMMANDS: &[(Symbol, &str)] = &[
+    (ELASTICITY, "@T"),
+    (SPHERE, "@O"),
+    (CYLINDER_START, "@Gs"),
+    (CYLINDER_POINT, "@Gc"),
+    (CYLINDER_END, "@Ge"),
+    (CONTOUR, "@#"),
+];
+
+/// The first of the characters given to names as they are seen
+const FIRST_NAMED: u32 = 0xE100;
+const LAST_NAMED: u32 = 0xF8FF;
+
+lazy_static! {
+    static ref NAMES: RwLock<Vec<String>> = RwLock::new(Vec::new());
+}
+
+/// The symbol standing for `label`, as it is written in an lstring
+pub fn intern(label: &str) -> Result<Symbol> {
+    if let Some(&(symbol, _)) = COMMANDS.iter().find(|(_, l)| *l == label) {
+        return Ok(symbol);
+    }
+    // Names are pushed and popped whole, so the table is still sound if a thread
+    // panicked while holding the lock
+    let mut names = NAMES.write().unwrap_or_else(PoisonError::into_inner);
+    let index = match names.iter().position(|n| n == label) {
+        Some(index) => index,
+        None => {
+            names.push(label.to_string());
+            names.len() - 1
+        }
+    };
+    match std::char::from_u32(FIRST_NAMED + index as u32) {
+        Some(symbol) if symbol as u32 <= LAST_NAMED => Ok(symbol),
+        _ => {
+            names.pop();
+            Err(Error::Unsupported(format!(
+                "too many named symbols, cannot add '{}'",
+                label
+            )))
+        }
+    }
+}
+
+/// How `symbol` is written in an lstring
+pub fn label(symbol: Symbol) -> String {
+    lookup(symbol).unwrap_or_else(|| symbol.to_string())
+}
+
+/// The label of a symbol written with more than one character
+pub fn lookup(symbol: Symbol) -> Option<String> {
+    if let Some(&(_, label)) = COMMANDS.iter().find(|(s, _)| *s == symbol) {
+        return Some(label.to_string());
+    }
+    let index = (symbol as u32).checked_sub(FIRST_NAMED)?;
+    NAMES
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(index as usize)
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intern_labels() {
+        let leaf = intern("~l").unwrap();
+        assert_eq!(intern("~l").unwrap(), leaf);
+        assert_ne!(intern("~p").unwrap(), leaf);
+        assert_eq!(label(leaf), "~l");
+        assert_eq!(intern("@T").unwrap(), ELASTICITY);
+        assert_eq!(label(ELASTICITY), "@T");
+        assert_eq!(label('F'), "F");
+        assert_eq!(lookup('F'), None);
+    }
+}
@@ -1,85 +1,289 @@
-use crate::config::get_config;
-use crate::graphics::{Vertex, VertexColor, VertexNormal, VertexPosition};
-use crate::lsys::{ActualParam, Element, LString};
+use crate::config::{find_config, get_config};
+use crate::contour::Contour;
+use crate::error::{Error, Result};
+use crate::geometry::{Mesh, Vertex};
+use crate::lsys::{ActualParam, Element, LString, Symbol};
+use crate::palette::Palette;
+use crate::polygon;
+use crate::surface::{self, Surface};
+use crate::symbol;
+use config::ConfigError;
 extern crate nalgebra_glm as glm;
-use glm::{Mat4, Vec3, Vec4};
-
-lazy_static! {
-    static ref DEFAULT_DISTANCE: f32 = get_config("turtle.default.distance");
-    static ref DEFAULT_SIZE: f32 = get_config("turtle.default.size");
-    static ref DEFAULT_ANGLE: f32 = get_config("turtle.default.angle");
-    static ref DEFAULT_COLOR: [f32; 3] = get_config("turtle.default.color");
-    static ref DEFAULT_ROTATION_STEP: i8 = get_config("turtle.default.rotation.step");
-    static ref DEFAULT_SHAPE_SEGMENTS: i8 = get_config("turtle.default.shape.segments");
-}
+use glm::{Mat4, Quat, Vec3, Vec4};
+use std::collections::HashMap;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct Turtle {
+    defaults: Defaults,
     state: TurtleState,
     stack: Vec<TurtleState>,
+    /// The polygons opened by `{` and not yet closed, innermost last. They are kept
+    /// apart from the state stack so that a polygon's vertices can be recorded from
+    /// inside branches.
+    polygons: Vec<Vec<PolygonVertex>>,
+    /// The surfaces drawn so far, so that each is looked up only once
+    surfaces: HashMap<Symbol, Arc<Surface>>,
+    /// The colours chosen by index with `'`
+    palette: Palette,
+    /// The cross-sections chosen by index with `@#`
+    contours: Vec<Arc<Outlines>>,
+    /// The largest branch size seen, which bounds the LOD radii worth trying
+    thickest: f32,
 }
 
 #[derive(Debug, Clone)]
 pub struct TurtleState {
-    transform: Mat4,
+    defaults: Defaults,
+    position: Vec3,
+    /// The rotation from the starting frame, kept to unit length so that the frame
+    /// stays orthonormal however many turns are made
+    orientation: Quat,
     color: Vec3,
     size: Option<f32>,
-    shape: Vec<ShapeVertex>,
+    shape: Arc<Outlines>,
+    /// How far each segment bends towards the tropism vector, set with `@T`
+    elasticity: f32,
+    color_index: f32,
+    /// The last control point of the generalized cylinder started with `@Gs`
+    cylinder: Option<ControlPoint>,
+    /// The end of the last tube drawn, left open while the next one may carry on from it
+    tip: Option<Tip>,
 }
 
-#[derive(Debug, Clone)]
+/// The turtle settings, read once when the turtle is created
+#[derive(Debug, Clone, Copy)]
+struct Defaults {
+    distance: f32,
+    size: f32,
+    angle: f32,
+    color: [f32; 3],
+    rotation_step: i8,
+    shape_segments: i8,
+    shrink: f32,
+    cylinder_segments: usize,
+    tropism: [f32; 3],
+    elasticity: f32,
+    caps: bool,
+    /// The radius below which rings have fewer points, in proportion to their radius
+    lod: Option<f32>,
+}
+
+/// The rings of a cross-section at each level of detail, with from three points
+/// (a triangular prism) up to `turtle.default.shape.segments`
+type Outlines = Vec<Vec<ShapeVertex>>;
+
+#[derive(Debug, Clone, PartialEq)]
 struct ShapeVertex {
     pos: Vec4,
     normal: Vec4,
 }
 
+/// Where a generalized cylinder passes through, and its ring there
+#[derive(Debug, Clone)]
+struct ControlPoint {
+    pos: Vec3,
+    heading: Vec3,
+    /// Carried along the cylinder from its start, so that the rings do not twist
+    right: Vec3,
+    size: f32,
+}
+
+#[derive(Debug, Clone)]
+struct Tip {
+    ring: Vec<ShapeVertex>,
+    heading: Vec3,
+    color: Vec3,
+}
+
+#[derive(Debug, Clone)]
+struct PolygonVertex {
+    pos: Vec3,
+    color: Vec3,
+}
+
 type DrawingOutput = Option<Vec<Vertex>>;
 
 impl Turtle {
-    pub fn new() -> Self {
-        Turtle {
-            state: TurtleState::new(),
+    pub fn new() -> Result<Self> {
+        let defaults = Defaults::new()?;
+        let palette = Palette::from_config(Vec3::from(defaults.color))?;
+        let contours = Contour::from_config(defaults.shape_segments as usize)?;
+        Ok(Turtle {
+            defaults,
+            state: TurtleState::new(defaults),
             stack: Vec::new(),
-        }
+            polygons: Vec::new(),
+            surfaces: HashMap::new(),
+            palette,
+            contours: contours.iter().map(TurtleState::outlines).collect(),
+            thickest: 0.0,
+        })
+    }
+
+    /// Draws rings thinner than `radius` with fewer points, down to three for the
+    /// thinnest, or every ring with all its points if `None`
+    pub fn set_lod(&mut self, radius: Option<f32>) {
+        self.defaults.lod = radius;
+        self.state.defaults.lod = radius;
     }
 
-    pub fn interpret(&mut self, lstring: &LString) -> Vec<Vertex> {
-        lstring
-            .into_iter()
-            .flat_map(|element| self.interpret_element(element).unwrap_or(Vec::new()))
+    /// A mesh of `lstring` for each of the LOD radii `radii`, most detailed first if
+    /// the radii are increasing
+    pub fn interpret_lods(lstring: &LString, radii: &[f32]) -> Result<Vec<Mesh>> {
+        radii
+            .iter()
+            .map(|&radius| {
+                let mut turtle = Turtle::new()?;
+                turtle.set_lod(Some(radius));
+                turtle.interpret(lstring)
+            })
             .collect()
     }
 
-    fn interpret_element(&mut self, element: &Element<ActualParam>) -> DrawingOutput {
-        match (element.symbol, &element.params[..]) {
-            ('F', []) => self.state.draw(*DEFAULT_DISTANCE, None),
+    /// The most detailed mesh made by `interpret` with at most `triangles` triangles,
+    /// doubling the LOD radius from the default branch size until the mesh fits or
+    /// every ring is down to three points. `interpret` is given a new turtle each time.
+    pub fn within_budget<F>(triangles: usize, mut interpret: F) -> Result<Mesh>
+    where
+        F: FnMut(&mut Turtle) -> Result<Mesh>,
+    {
+        let mut turtle = Turtle::new()?;
+        let mut mesh = interpret(&mut turtle)?;
+        let (fewest, most) = (3.0, turtle.defaults.shape_segments as f32);
+        // Rings are down to three points once the radius is this much thicker
+        let coarsest = turtle.thickest * most / fewest;
+        let mut radius = turtle.defaults.size;
+        while mesh.indices().len() / 3 > triangles && radius < 2.0 * coarsest {
+            let mut turtle = Turtle::new()?;
+            turtle.set_lod(Some(radius));
+            mesh = interpret(&mut turtle)?;
+            radius *= 2.0;
+        }
+        Ok(mesh)
+    }
+
+    pub fn interpret(&mut self, lstring: &LString) -> Result<Mesh> {
+        let mut mesh = Mesh::new();
+        for element in lstring {
+            if let Some(mut vertices) = self.interpret_element(element)? {
+                mesh.append(&mut vertices);
+            }
+        }
+        if let Some(mut vertices) = self.state.cap_tip() {
+            mesh.append(&mut vertices);
+        }
+        Ok(mesh)
+    }
+
+    /// Interprets elements as they are produced, for instance by `LSystem::stream`,
+    /// so that the string is never stored whole.
+    pub fn interpret_stream<I>(&mut self, elements: I) -> Result<Mesh>
+    where
+        I: IntoIterator<Item = Result<Element<ActualParam>>>,
+    {
+        let mut mesh = Mesh::new();
+        for element in elements {
+            if let Some(mut vertices) = self.interpret_element(&element?)? {
+                mesh.append(&mut vertices);
+            }
+        }
+        if let Some(mut vertices) = self.state.cap_tip() {
+            mesh.append(&mut vertices);
+        }
+        Ok(mesh)
+    }
+
+    fn interpret_element(&mut self, element: &Element<ActualParam>) -> Result<DrawingOutput> {
+        let Defaults {
+            distance, angle, ..
+        } = self.defaults;
+        let output = match (element.symbol, &element.params[..]) {
+            ('F', []) => self.state.draw(distance, None),
             ('F', [x]) => self.state.draw(*x, None),
             ('F', [x, y]) => self.state.draw(*x, Some(*y)),
-            ('f', []) => self.state.mov(*DEFAULT_DISTANCE),
-            ('f', [x]) => self.state.mov(*x),
-            ('+', []) => self.state.turn(*DEFAULT_ANGLE),
+            ('f', []) => self.record_move(distance),
+            ('f', [x]) => self.record_move(*x),
+            ('G', []) => self.state.mov(distance),
+            ('G', [x]) => self.state.mov(*x),
+            ('+', []) => self.state.turn(angle),
             ('+', [x]) => self.state.turn(*x),
-            ('-', []) => self.state.turn(-*DEFAULT_ANGLE),
+            ('-', []) => self.state.turn(-angle),
             ('-', [x]) => self.state.turn(-*x),
-            ('/', []) => self.state.roll(*DEFAULT_ANGLE),
+            ('/', []) => self.state.roll(angle),
             ('/', [x]) => self.state.roll(*x),
-            ('\\', []) => self.state.roll(-*DEFAULT_ANGLE),
+            ('\\', []) => self.state.roll(-angle),
             ('\\', [x]) => self.state.roll(-*x),
-            ('^', []) => self.state.pitch(*DEFAULT_ANGLE),
+            ('^', []) => self.state.pitch(angle),
             ('^', [x]) => self.state.pitch(*x),
-            ('&', []) => self.state.pitch(-*DEFAULT_ANGLE),
+            ('&', []) => self.state.pitch(-angle),
             ('&', [x]) => self.state.pitch(-*x),
             ('`', [x, y, z]) => self.state.color(*x, *y, *z),
+            ('|', []) => self.state.reverse(),
+            ('$', []) => self.state.level(),
+            ('!', []) => {
+                let size = self.state.size.unwrap_or(self.defaults.size);
+                self.state.size = Some((size - self.defaults.shrink).max(0.0));
+                None
+            }
+            ('!', [w]) => {
+                self.state.size = Some(*w);
+                None
+            }
+            ('\'', []) => self.set_color_index(self.state.color_index.floor() + 1.0),
+            ('\'', [i]) => self.set_color_index(*i),
+            (symbol::SPHERE, []) => {
+                let size = self.state.size.unwrap_or(self.defaults.size);
+                self.state.sphere(size)
+            }
+            (symbol::SPHERE, [r]) => self.state.sphere(*r),
+            (symbol::CONTOUR, [i]) => match self.contours.get(i.max(0.0) as usize) {
+                Some(shape) => {
+                    self.state.shape = shape.clone();
+                    None
+                }
+                None => {
+                    return Err(Error::Config(ConfigError::Message(format!(
+                        "there is no contour {} in turtle.contours",
+                        i
+                    ))))
+                }
+            },
+            (symbol::CYLINDER_START, []) => self.state.start_cylinder(),
+            (symbol::CYLINDER_POINT, []) => self.state.extend_cylinder(None, false),
+            (symbol::CYLINDER_POINT, [n]) => self.state.extend_cylinder(Some(*n), false),
+            (symbol::CYLINDER_END, []) => self.state.extend_cylinder(None, true),
+            (symbol::CYLINDER_END, [n]) => self.state.extend_cylinder(Some(*n), true),
             ('[', []) => {
                 self.push_state();
                 None
             }
             (']', []) => {
+                let cap = self.state.cap_tip();
                 self.pop_state();
+                cap
+            }
+            ('{', []) => {
+                self.polygons.push(Vec::new());
+                None
+            }
+            ('.', []) => {
+                self.record_vertex();
+                None
+            }
+            ('}', []) => self.close_polygon(),
+            (symbol::ELASTICITY, [e]) => {
+                self.state.elasticity = *e;
                 None
             }
+            (symbol, []) if surface::name(symbol).is_some() => self.place_surface(symbol, 1.0)?,
+            (symbol, [s]) if surface::name(symbol).is_some() => self.place_surface(symbol, *s)?,
             _ => None,
+        };
+        if let Some(size) = self.state.size {
+            self.thickest = self.thickest.max(size);
         }
+        Ok(output)
     }
 
     fn push_state(&mut self) {
@@ -91,51 +295,178 @@ impl Turtle {
             self.state = state;
         }
     }
+
+    fn set_color_index(&mut self, index: f32) -> DrawingOutput {
+        self.state.color_index = index;
+        self.state.color = self.palette.color(index);
+        None
+    }
+
+    /// Draws the surface `symbol` stands for in the turtle's frame, scaled by `scale`
+    /// and in the turtle's colour
+    fn place_surface(&mut self, symbol: Symbol, scale: f32) -> Result<DrawingOutput> {
+        let surface = match self.surfaces.get(&symbol) {
+            Some(surface) => surface.clone(),
+            None => {
+                let name = surface::name(symbol).unwrap();
+                let surface = Surface::named(&name)?;
+                self.surfaces.insert(symbol, surface.clone());
+                surface
+            }
+        };
+        let transform = self.state.transform() * glm::scaling(&Vec3::new(scale, scale, scale));
+        let rotation = glm::quat_to_mat3(&self.state.orientation);
+        let color = self.state.color;
+        Ok(Some(
+            surface
+                .vertices()
+                .iter()
+                .map(|v| {
+                    let pos = transform * Vec4::new(v.pos[0], v.pos[1], v.pos[2], 1.0);
+                    let norm = glm::normalize(&(rotation * Vec3::from(v.norm)));
+                    Vertex {
+                        pos: [pos.x, pos.y, pos.z],
+                        col: [color.x, color.y, color.z],
+                        norm: [norm.x, norm.y, norm.z],
+                    }
+                })
+                .collect(),
+        ))
+    }
+
+    /// Moves without drawing, marking a vertex of the open polygon if there is one
+    fn record_move(&mut self, distance: f32) -> DrawingOutput {
+        self.state.mov(distance);
+        if !self.polygons.is_empty() {
+            self.record_vertex();
+        }
+        None
+    }
+
+    fn record_vertex(&mut self) {
+        let pos = self.state.position();
+        let color = self.state.color;
+        if let Some(polygon) = self.polygons.last_mut() {
+            if polygon.last().map(|v| v.pos) != Some(pos) {
+                polygon.push(PolygonVertex { pos, color });
+            }
+        }
+    }
+
+    /// Fills the innermost open polygon, lit from the side its vertices turn
+    /// counterclockwise around
+    fn close_polygon(&mut self) -> DrawingOutput {
+        let mut polygon = self.polygons.pop()?;
+        if polygon.len() > 1 && polygon[0].pos == polygon[polygon.len() - 1].pos {
+            polygon.pop();
+        }
+        let points: Vec<Vec3> = polygon.iter().map(|v| v.pos).collect();
+        let normal = polygon::normal(&points)?;
+        Some(
+            polygon::triangulate(&points)
+                .iter()
+                .flatten()
+                .map(|&i| Vertex {
+                    pos: [points[i].x, points[i].y, points[i].z],
+                    col: [polygon[i].color.x, polygon[i].color.y, polygon[i].color.z],
+                    norm: [normal.x, normal.y, normal.z],
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Defaults {
+    fn new() -> Result<Self> {
+        Ok(Defaults {
+            distance: get_config("turtle.default.distance")?,
+            size: get_config("turtle.default.size")?,
+            angle: get_config("turtle.default.angle")?,
+            color: get_config("turtle.default.color")?,
+            rotation_step: get_config("turtle.default.rotation.step")?,
+            shape_segments: get_config("turtle.default.shape.segments")?,
+            shrink: find_config("turtle.default.shrink")?.unwrap_or(0.0),
+            cylinder_segments: find_config("turtle.cylinder.segments")?.unwrap_or(8),
+            tropism: find_config("turtle.tropism.vector")?.unwrap_or([0.0, 0.0, 0.0]),
+            elasticity: find_config("turtle.tropism.elasticity")?.unwrap_or(0.0),
+            caps: find_config("turtle.caps")?.unwrap_or(false),
+            lod: find_config("turtle.lod.radius")?,
+        })
+    }
 }
 
 impl TurtleState {
-    fn new() -> Self {
+    fn new(defaults: Defaults) -> Self {
         TurtleState {
-            transform: glm::identity(),
-            color: glm::make_vec3(&*DEFAULT_COLOR),
+            defaults,
+            position: Vec3::zeros(),
+            orientation: glm::quat_identity(),
+            color: glm::make_vec3(&defaults.color),
             size: None,
-            shape: Self::default_shape(),
+            shape: Self::outlines(&Contour::circle(defaults.shape_segments as usize)),
+            elasticity: defaults.elasticity,
+            color_index: 0.0,
+            cylinder: None,
+            tip: None,
         }
     }
 
-    fn default_shape() -> Vec<ShapeVertex> {
-        let n = *DEFAULT_SHAPE_SEGMENTS;
-        let x = Vec3::x();
-        let y = Vec3::y();
-        (0..n)
-            .map(|i| glm::rotate_vec3(&x, (i as f32 * 360.0 / n as f32).to_radians(), &y))
-            .map(|v| ShapeVertex {
-                pos: Vec4::new(v.x, v.y, v.z, 1.0),
-                normal: Vec4::new(v.x, v.y, v.z, 1.0),
+    /// The rings of a contour at each level of detail
+    fn outlines(contour: &Contour) -> Arc<Outlines> {
+        let n = contour.points().len();
+        Arc::new(
+            (n.min(3)..=n)
+                .map(|k| Self::shape(&contour.resample(k)))
+                .collect(),
+        )
+    }
+
+    /// The ring of a contour, lying across the heading
+    fn shape(contour: &Contour) -> Vec<ShapeVertex> {
+        contour
+            .points()
+            .iter()
+            .zip(contour.normals())
+            .map(|(p, n)| ShapeVertex {
+                pos: Vec4::new(p.x, 0.0, p.y, 1.0),
+                normal: Vec4::new(n.x, 0.0, n.y, 0.0),
             })
             .collect()
     }
+
     fn position(&self) -> Vec3 {
-        glm::vec4_to_vec3(&glm::column(&self.transform, 3))
+        self.position
     }
 
     fn right(&self) -> Vec3 {
-        glm::vec4_to_vec3(&glm::column(&self.transform, 0))
+        glm::quat_rotate_vec3(&self.orientation, &Vec3::x())
     }
 
     fn heading(&self) -> Vec3 {
-        glm::vec4_to_vec3(&glm::column(&self.transform, 1))
+        glm::quat_rotate_vec3(&self.orientation, &Vec3::y())
     }
 
     fn up(&self) -> Vec3 {
-        glm::vec4_to_vec3(&glm::column(&self.transform, 2))
+        glm::quat_rotate_vec3(&self.orientation, &Vec3::z())
+    }
+
+    /// The turtle's frame, with x to the right, y along the heading and z up
+    fn transform(&self) -> Mat4 {
+        glm::translation(&self.position) * glm::quat_to_mat4(&self.orientation)
     }
 
     pub fn mov(&mut self, distance: f32) -> DrawingOutput {
-        self.transform = glm::translation(&(self.heading() * distance)) * self.transform;
+        self.position += self.heading() * distance;
         None
     }
 
+    /// Turns the frame by `angle` radians about `axis`, normalizing the orientation so
+    /// that rounding errors do not build up into scale or skew
+    fn rotate(&mut self, angle: f32, axis: &Vec3) {
+        let rotation = glm::quat_angle_axis(angle, axis);
+        self.orientation = glm::quat_normalize(&(rotation * self.orientation));
+    }
+
     pub fn turn(&mut self, angle: f32) -> DrawingOutput {
         self.rot(-angle, &self.up())
     }
@@ -148,36 +479,130 @@ impl TurtleState {
         self.rot(angle, &self.heading())
     }
 
+    /// Turns around, as `+(180)` would but without sweeping the joint
+    fn reverse(&mut self) -> DrawingOutput {
+        let (right, heading) = (self.right(), self.heading());
+        self.set_frame(-right, -heading);
+        None
+    }
+
+    /// Rolls about the heading so that the right (and left) vector is horizontal,
+    /// unless the heading is vertical
+    fn level(&mut self) -> DrawingOutput {
+        let heading = self.heading();
+        let horizontal = heading.cross(&Vec3::y());
+        if glm::length(&horizontal) > 1e-6 {
+            let mut right = glm::normalize(&horizontal);
+            if glm::dot(&right, &self.right()) < 0.0 {
+                right = -right;
+            }
+            self.set_frame(right, heading);
+        }
+        None
+    }
+
+    /// Replaces the orientation, keeping the frame right-handed
+    fn set_frame(&mut self, right: Vec3, heading: Vec3) {
+        let frame = frame(&self.position(), &heading, &right);
+        self.orientation = glm::quat_normalize(&glm::to_quat(&frame));
+    }
+
+    /// A sphere of radius `r` centred on the turtle
+    fn sphere(&self, r: f32) -> DrawingOutput {
+        let n = self.ring_points(r).max(3);
+        let rings = (n / 2).max(2);
+        let center = self.position();
+        let point = |i: usize, j: usize| {
+            let theta = std::f32::consts::PI * i as f32 / rings as f32;
+            let phi = 2.0 * std::f32::consts::PI * j as f32 / n as f32;
+            let normal = Vec3::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            );
+            let pos = center + normal * r;
+            ShapeVertex {
+                pos: Vec4::new(pos.x, pos.y, pos.z, 1.0),
+                normal: Vec4::new(normal.x, normal.y, normal.z, 0.0),
+            }
+        };
+        let mut vertices = Vec::with_capacity(rings * n * 6);
+        for i in 0..rings {
+            for j in 0..n {
+                let (a, b, c, d) = (
+                    point(i, j),
+                    point(i + 1, j),
+                    point(i + 1, j + 1),
+                    point(i, j + 1),
+                );
+                if i > 0 {
+                    vertices.append(&mut self.triangle(&a, &d, &c));
+                }
+                if i + 1 < rings {
+                    vertices.append(&mut self.triangle(&a, &c, &b));
+                }
+            }
+        }
+        Some(vertices)
+    }
+
     fn draw(&mut self, distance: f32, new_size: Option<f32>) -> DrawingOutput {
+        if self.cylinder.is_some() {
+            // The cylinder is swept when its next control point is reached
+            self.mov(distance);
+            if new_size.is_some() {
+                self.size = new_size;
+            }
+            self.bend();
+            return None;
+        }
         if self.size.is_none() {
             self.size = new_size;
         }
-        let shape1 = self.transformed_shape();
+        let (frame1, size1, heading1) = (self.transform(), self.size(), self.heading());
         self.mov(distance);
         if new_size.is_some() {
             self.size = new_size;
         }
-        let shape2 = self.transformed_shape();
-        Some(self.tube(shape1, shape2))
+        // Bending before the end ring is placed makes it the start ring of the next
+        // segment, so the joint is welded rather than kinked
+        self.bend();
+        let size2 = self.size();
+        let shape1 = self.ring(&frame1, size1, self.ring_points(size1));
+        let shape2 = self.transformed_shape(self.ring_points(size2));
+        let heading2 = self.heading();
+        Some(self.segment(shape1, heading1, shape2, heading2))
+    }
+
+    /// Turns the heading towards the tropism vector T, about the axis H×T and by
+    /// the angle e·|H×T| in radians, as after each segment in ABOP.
+    fn bend(&mut self) {
+        let tropism = glm::make_vec3(&self.defaults.tropism);
+        let axis = self.heading().cross(&tropism);
+        let angle = self.elasticity * glm::length(&axis);
+        if angle.abs() > f32::EPSILON {
+            self.rotate(angle, &axis);
+        }
     }
 
     fn rot(&mut self, angle: f32, axis: &Vec3) -> DrawingOutput {
-        let pos = &self.position();
-        let to_origin = glm::translation(&(Vec3::zeros() - pos));
-        let to_pos = glm::translation(pos);
+        if self.cylinder.is_some() {
+            self.rotate(angle.to_radians(), axis);
+            return None;
+        }
 
-        let steps = (angle as i8 / *DEFAULT_ROTATION_STEP).abs();
-        let rotation = glm::rotation((angle / steps as f32).to_radians(), axis);
-        Some(
-            (0..steps)
-                .flat_map(|_| {
-                    let shape1 = self.transformed_shape();
-                    self.transform = to_pos * rotation * to_origin * self.transform;
-                    let shape2 = self.transformed_shape();
-                    self.tube(shape1, shape2)
-                })
-                .collect(),
-        )
+        let steps = (angle as i8 / self.defaults.rotation_step).abs();
+        let step = (angle / steps as f32).to_radians();
+        let size = self.size();
+        let points = self.ring_points(size);
+        let mut vertices = Vec::new();
+        for _ in 0..steps {
+            let (shape1, heading1) = (self.transformed_shape(points), self.heading());
+            self.rotate(step, axis);
+            let (shape2, heading2) = (self.transformed_shape(points), self.heading());
+            vertices.append(&mut self.segment(shape1, heading1, shape2, heading2));
+        }
+        Some(vertices)
     }
 
     pub fn color(&mut self, r: f32, g: f32, b: f32) -> DrawingOutput {
@@ -187,30 +612,171 @@ impl TurtleState {
         None
     }
 
-    fn transformed_shape(&mut self) -> Vec<ShapeVertex> {
-        let s = *self.size.get_or_insert(*DEFAULT_SIZE);
+    fn start_cylinder(&mut self) -> DrawingOutput {
+        self.cylinder = Some(self.control_point());
+        None
+    }
+
+    /// Sweeps the generalized cylinder from its last control point to the turtle
+    /// along a Hermite curve, whose tangents are the headings at either end, in
+    /// `segments` steps. Each ring's right vector is the previous one made
+    /// perpendicular to the curve, so the rings follow the curve without twisting.
+    fn extend_cylinder(&mut self, segments: Option<f32>, end: bool) -> DrawingOutput {
+        let start = self.cylinder.take()?;
+        let mut point = self.control_point();
+        let segments = segments.map_or(self.defaults.cylinder_segments, |n| n.max(1.0) as usize);
+        let length = glm::distance(&start.pos, &point.pos);
+        let (m0, m1) = (start.heading * length, point.heading * length);
+
+        let mut tangent = start.heading;
+        let mut right = start.right;
+        let points = self.ring_points(start.size);
+        let mut ring = self.ring(&frame(&start.pos, &tangent, &right), start.size, points);
+        let mut heading = tangent;
+        let mut vertices = Vec::with_capacity(segments * ring.len() * 6);
+        for k in 1..=segments {
+            let t = k as f32 / segments as f32;
+            let (pos, derivative) = hermite(&start.pos, &m0, &point.pos, &m1, t);
+            if glm::length(&derivative) > 1e-6 {
+                tangent = glm::normalize(&derivative);
+            }
+            right = perpendicular(&right, &tangent);
+            let size = start.size + (point.size - start.size) * t;
+            let points = self.ring_points(size);
+            let next = self.ring(&frame(&pos, &tangent, &right), size, points);
+            vertices.append(&mut self.segment(ring, heading, next.clone(), tangent));
+            ring = next;
+            heading = tangent;
+        }
+        point.right = right;
+        if !end {
+            self.cylinder = Some(point);
+        }
+        Some(vertices)
+    }
+
+    fn control_point(&mut self) -> ControlPoint {
+        ControlPoint {
+            pos: self.position(),
+            heading: self.heading(),
+            right: self.right(),
+            size: self.size(),
+        }
+    }
+
+    /// The branch size, which is the default until it is first set
+    fn size(&mut self) -> f32 {
+        *self.size.get_or_insert(self.defaults.size)
+    }
+
+    /// The number of points in a ring of radius `s`: all of the cross-section's down
+    /// to the LOD radius, and fewer in proportion below it
+    fn ring_points(&self, s: f32) -> usize {
+        let (fewest, most) = (self.shape[0].len(), self.shape[self.shape.len() - 1].len());
+        match self.defaults.lod {
+            Some(radius) if s < radius => {
+                ((most as f32 * s / radius).ceil() as usize).clamp(fewest, most)
+            }
+            _ => most,
+        }
+    }
+
+    fn transformed_shape(&mut self, points: usize) -> Vec<ShapeVertex> {
+        let s = self.size();
+        self.ring(&self.transform(), s, points)
+    }
+
+    /// The cross-section of size `s` with `points` points placed in `frame`
+    fn ring(&self, frame: &Mat4, s: f32, points: usize) -> Vec<ShapeVertex> {
         let scaling = glm::scaling(&Vec3::new(s, s, s));
-        self.shape
+        self.shape[points - self.shape[0].len()]
             .iter()
             .map(|ShapeVertex { pos, normal }| ShapeVertex {
-                pos: self.transform * scaling * pos,
-                normal: self.transform * normal,
+                pos: frame * scaling * pos,
+                normal: frame * normal,
             })
             .collect()
     }
 
-    fn tube(&self, shape1: Vec<ShapeVertex>, shape2: Vec<ShapeVertex>) -> Vec<Vertex> {
-        let n = shape1.len();
-        (0..n)
-            .flat_map(|i| {
-                let next = (i + 1) % n;
-                let mut t = self.triangle(&shape2[i], &shape1[i], &shape2[next]);
-                t.append(&mut self.triangle(&shape2[next], &shape1[i], &shape1[next]));
-                t
+    /// The tube from `shape1` to `shape2`, which face `heading1` and `heading2`. With
+    /// `turtle.caps` set, a tube that does not carry on from the last one closes the
+    /// end of that one and its own start.
+    fn segment(
+        &mut self,
+        shape1: Vec<ShapeVertex>,
+        heading1: Vec3,
+        shape2: Vec<ShapeVertex>,
+        heading2: Vec3,
+    ) -> Vec<Vertex> {
+        let mut vertices = Vec::new();
+        if self.defaults.caps {
+            if self.tip.as_ref().map(|tip| &tip.ring) != Some(&shape1) {
+                if let Some(mut cap) = self.cap_tip() {
+                    vertices.append(&mut cap);
+                }
+                vertices.append(&mut self.cap(&shape1, -heading1, self.color));
+            }
+            self.tip = Some(Tip {
+                ring: shape2.clone(),
+                heading: heading2,
+                color: self.color,
+            });
+        }
+        vertices.append(&mut self.tube(shape1, shape2));
+        vertices
+    }
+
+    /// Closes the end of the last tube drawn, if it is still open
+    fn cap_tip(&mut self) -> DrawingOutput {
+        let tip = self.tip.take()?;
+        Some(self.cap(&tip.ring, tip.heading, tip.color))
+    }
+
+    /// Fills `ring`, facing `normal`
+    fn cap(&self, ring: &[ShapeVertex], normal: Vec3, color: Vec3) -> Vec<Vertex> {
+        let points: Vec<Vec3> = ring.iter().map(|v| glm::vec4_to_vec3(&v.pos)).collect();
+        let facing = match polygon::normal(&points) {
+            Some(n) => glm::dot(&n, &normal) > 0.0,
+            None => return Vec::new(),
+        };
+        let norm = [normal.x, normal.y, normal.z];
+        let vertex = |i: usize| Vertex {
+            pos: [points[i].x, points[i].y, points[i].z],
+            col: [color.x, color.y, color.z],
+            norm,
+        };
+        polygon::triangulate(&points)
+            .iter()
+            .flat_map(|&[a, b, c]| {
+                if facing {
+                    [vertex(a), vertex(b), vertex(c)]
+                } else {
+                    [vertex(a), vertex(c), vertex(b)]
+                }
             })
             .collect()
     }
 
+    /// The strip of triangles joining two rings, which may have different numbers of
+    /// points. Both go round the contour from the same start, so each step moves along
+    /// whichever ring has the nearer next point.
+    fn tube(&self, shape1: Vec<ShapeVertex>, shape2: Vec<ShapeVertex>) -> Vec<Vertex> {
+        let (n, m) = (shape1.len(), shape2.len());
+        let mut vertices = Vec::with_capacity((n + m) * 3);
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            let mut t = if (j + 1) * n <= (i + 1) * m {
+                j += 1;
+                self.triangle(&shape2[j - 1], &shape1[i % n], &shape2[j % m])
+            } else {
+                i += 1;
+                self.triangle(&shape2[j % m], &shape1[i - 1], &shape1[i % n])
+            };
+            vertices.append(&mut t);
+        }
+        vertices
+    }
+
     fn triangle(&self, v1: &ShapeVertex, v2: &ShapeVertex, v3: &ShapeVertex) -> Vec<Vertex> {
         let color = self.color;
         vec![
@@ -222,21 +788,67 @@ impl TurtleState {
 
     fn vertex(&self, v: &ShapeVertex, color: Vec3) -> Vertex {
         Vertex {
-            pos: VertexPosition::new([v.pos.x, v.pos.y, v.pos.z]),
-            col: VertexColor::new([color.x, color.y, color.z]),
-            norm: VertexNormal::new([v.normal.x, v.normal.y, v.normal.z]),
+            pos: [v.pos.x, v.pos.y, v.pos.z],
+            col: [color.x, color.y, color.z],
+            norm: [v.normal.x, v.normal.y, v.normal.z],
         }
     }
 }
 
+/// The turtle frame at `pos` with the given heading and right vectors
+fn frame(pos: &Vec3, heading: &Vec3, right: &Vec3) -> Mat4 {
+    let up = right.cross(heading);
+    let mut frame = Mat4::identity();
+    for (i, v) in [right, heading, &up, pos].iter().enumerate() {
+        let w = if i == 3 { 1.0 } else { 0.0 };
+        frame.set_column(i, &Vec4::new(v.x, v.y, v.z, w));
+    }
+    frame
+}
+
+/// The point and derivative at `t` of the cubic Hermite curve from `p0` to `p1`
+/// with tangents `m0` and `m1`
+fn hermite(p0: &Vec3, m0: &Vec3, p1: &Vec3, m1: &Vec3, t: f32) -> (Vec3, Vec3) {
+    let (t2, t3) = (t * t, t * t * t);
+    let point = p0 * (2.0 * t3 - 3.0 * t2 + 1.0)
+        + m0 * (t3 - 2.0 * t2 + t)
+        + p1 * (3.0 * t2 - 2.0 * t3)
+        + m1 * (t3 - t2);
+    let derivative = p0 * (6.0 * t2 - 6.0 * t)
+        + m0 * (3.0 * t2 - 4.0 * t + 1.0)
+        + p1 * (6.0 * t - 6.0 * t2)
+        + m1 * (3.0 * t2 - 2.0 * t);
+    (point, derivative)
+}
+
+/// `v` with its component along the unit vector `axis` removed, normalized
+fn perpendicular(v: &Vec3, axis: &Vec3) -> Vec3 {
+    let p = v - axis * glm::dot(v, axis);
+    if glm::length(&p) > 1e-6 {
+        glm::normalize(&p)
+    } else {
+        // v is along the axis, so any perpendicular will do
+        let other = if axis.x.abs() < 0.9 {
+            Vec3::x()
+        } else {
+            Vec3::y()
+        };
+        glm::normalize(&axis.cross(&other))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::parser::{parse_lstring, parse_lsys};
     use approx::assert_relative_eq;
+    use glm::Vec2;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
     #[test]
     fn test_mov() {
-        let mut turtle = Turtle::new();
+        let mut turtle = Turtle::new().unwrap();
         turtle.state.mov(0.5);
         println!("{:?}", turtle);
         assert_relative_eq!(turtle.state.position(), Vec3::new(0.0, 0.5, 0.0));
@@ -244,22 +856,22 @@ mod tests {
 
     #[test]
     fn test_turn() {
-        let mut turtle = Turtle::new();
+        let mut turtle = Turtle::new().unwrap();
         turtle.state.turn(90.0);
         turtle.state.mov(0.5);
         assert_relative_eq!(
             turtle.state.position(),
             Vec3::new(0.5, 0.0, 0.0),
-            epsilon = std::f32::EPSILON * 2.0
+            epsilon = f32::EPSILON * 2.0
         );
     }
 
     #[test]
     fn test_move_then_turn() {
-        let mut turtle = Turtle::new();
+        let mut turtle = Turtle::new().unwrap();
         println!(
             "start {:?} position:{}, heading:{}, up:{}, right:{}",
-            turtle.state.transform.data,
+            turtle.state.transform().data,
             turtle.state.position(),
             turtle.state.heading(),
             turtle.state.up(),
@@ -268,7 +880,7 @@ mod tests {
         turtle.state.mov(1.0);
         println!(
             "after first move {:?} position:{}, heading:{}, up:{}, right:{}",
-            turtle.state.transform.data,
+            turtle.state.transform().data,
             turtle.state.position(),
             turtle.state.heading(),
             turtle.state.up(),
@@ -277,7 +889,7 @@ mod tests {
         turtle.state.turn(90.0);
         println!(
             "after turn {:?} position:{}, heading:{}, up:{}, right:{}",
-            turtle.state.transform.data,
+            turtle.state.transform().data,
             turtle.state.position(),
             turtle.state.heading(),
             turtle.state.up(),
@@ -286,7 +898,7 @@ mod tests {
         turtle.state.mov(0.5);
         println!(
             "after second move {:?} position:{}, heading:{}, up:{}, right:{}",
-            turtle.state.transform.data,
+            turtle.state.transform().data,
             turtle.state.position(),
             turtle.state.heading(),
             turtle.state.up(),
@@ -295,35 +907,373 @@ mod tests {
         assert_relative_eq!(
             turtle.state.position(),
             Vec3::new(0.5, 1.0, 0.0),
-            epsilon = std::f32::EPSILON * 2.0
+            epsilon = f32::EPSILON * 2.0
         );
     }
 
     #[test]
     fn test_pitch() {
-        let mut turtle = Turtle::new();
+        let mut turtle = Turtle::new().unwrap();
         turtle.state.pitch(90.0);
         turtle.state.mov(0.5);
         println!("{:?}", turtle);
         assert_relative_eq!(
             turtle.state.position(),
             Vec3::new(0.0, 0.0, 0.5),
-            epsilon = std::f32::EPSILON * 2.0
+            epsilon = f32::EPSILON * 2.0
         );
     }
 
     #[test]
     fn test_roll() {
-        let mut turtle = Turtle::new();
+        let mut turtle = Turtle::new().unwrap();
         turtle.state.roll(90.0);
         turtle.state.mov(0.5);
         println!("{:?}", turtle);
         assert_relative_eq!(turtle.state.position(), Vec3::new(0.0, 0.5, 0.0));
     }
 
+    #[test]
+    fn test_frame_stays_orthonormal() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut turtle = Turtle::new().unwrap();
+        let state = &mut turtle.state;
+        for _ in 0..1_000_000 {
+            let angle = rng.gen_range(-180.0f32, 180.0).to_radians();
+            let axis = match rng.gen_range(0, 3) {
+                0 => state.up(),
+                1 => state.right(),
+                _ => state.heading(),
+            };
+            state.rotate(angle, &axis);
+        }
+        let rotation = glm::mat4_to_mat3(&state.transform());
+        assert_relative_eq!(
+            rotation.transpose() * rotation,
+            glm::Mat3::identity(),
+            epsilon = 1e-5
+        );
+        assert_relative_eq!(glm::determinant(&rotation), 1.0, epsilon = 1e-5);
+        assert_relative_eq!(
+            state.right().cross(&state.heading()),
+            state.up(),
+            epsilon = 1e-5
+        );
+    }
+
+    #[test]
+    fn test_polygon() {
+        let lstring = parse_lstring("{.f.+f.[{.f.+f.}]+f.}").unwrap();
+        let mut turtle = Turtle::new().unwrap();
+        let mut filled = Vec::new();
+        for element in &lstring {
+            let output = turtle.interpret_element(element).unwrap();
+            if element.symbol == '}' {
+                filled.push(output.unwrap());
+            }
+        }
+        assert!(turtle.polygons.is_empty());
+        // The triangle nested in the branch is filled first, then the square
+        assert_eq!(filled[0].len(), 3);
+        assert_eq!(filled[1].len(), 6);
+        let square: Vec<_> = filled[1].iter().map(|v| v.pos).collect();
+        assert!(square.contains(&[0.0, 0.0, 0.0]));
+        for v in filled.iter().flatten() {
+            assert_relative_eq!(v.norm[2], -1.0, epsilon = 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_surface() {
+        let path = std::env::temp_dir().join("abop_test_surface.obj");
+        std::fs::write(&path, "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+        let source = format!("#set surface.t=\"{}\"\nf~t(2)", path.display());
+        let lsystem = parse_lsys(&source).unwrap();
+        assert_eq!(lsystem.current.to_string(), "f~t(2)");
+        let mesh = Turtle::new().unwrap().interpret(&lsystem.current).unwrap();
+        let vertices = mesh.vertices();
+        assert_eq!(vertices.len(), 3);
+        assert_relative_eq!(Vec3::from(vertices[1].pos), Vec3::new(2.0, 0.1, 0.0));
+        assert_relative_eq!(Vec3::from(vertices[2].pos), Vec3::new(0.0, 2.1, 0.0));
+        assert_relative_eq!(Vec3::from(vertices[0].norm), Vec3::z());
+        assert_eq!(vertices[0].col, [1.0, 0.6, 0.0]);
+
+        let undefined = parse_lstring("~u").unwrap();
+        assert!(Turtle::new().unwrap().interpret(&undefined).is_err());
+    }
+
+    #[test]
+    fn test_tropism() {
+        let mut turtle = Turtle::new().unwrap();
+        turtle.defaults.tropism = [0.0, -1.0, 0.0];
+        turtle.state.defaults.tropism = [0.0, -1.0, 0.0];
+        let lstring = parse_lstring("@T(0.5)[@T(0)+F]+F").unwrap();
+        for element in &lstring {
+            turtle.interpret_element(element).unwrap();
+        }
+        // Heading along x, the segment bends down by half a radian about -z, the
+        // elasticity set inside the branch being dropped at `]`
+        assert_relative_eq!(turtle.state.elasticity, 0.5);
+        assert_relative_eq!(
+            turtle.state.heading(),
+            Vec3::new(0.5f32.cos(), -0.5f32.sin(), 0.0),
+            epsilon = 1e-5
+        );
+        assert_relative_eq!(
+            turtle.state.position(),
+            Vec3::new(0.1, 0.0, 0.0),
+            epsilon = 1e-6
+        );
+    }
+
+    #[test]
+    fn test_standard_commands() {
+        let mut turtle = Turtle::new().unwrap();
+        turtle.palette = Palette::new(vec![Vec3::x(), Vec3::y(), Vec3::z()]).unwrap();
+        let lstring = parse_lstring("&(30)+(40)$|!(0.5)!'(1)''").unwrap();
+        for element in &lstring {
+            turtle.interpret_element(element).unwrap();
+        }
+        let state = &turtle.state;
+        assert_relative_eq!(state.right().y, 0.0, epsilon = 1e-6);
+        // Level, the up vector is as near vertical as the heading allows
+        let heading = state.heading();
+        assert_relative_eq!(
+            state.up().y.abs(),
+            (1.0 - heading.y * heading.y).sqrt(),
+            epsilon = 1e-5
+        );
+        assert_relative_eq!(
+            glm::dot(&state.right(), &state.heading()),
+            0.0,
+            epsilon = 1e-6
+        );
+        assert_relative_eq!(
+            state.right().cross(&state.heading()),
+            state.up(),
+            epsilon = 1e-5
+        );
+        assert_relative_eq!(state.size.unwrap(), 0.5 - turtle.defaults.shrink);
+        assert_eq!(state.color_index, 3.0);
+        assert_eq!(state.color, Vec3::z());
+        turtle.set_color_index(0.5);
+        assert_relative_eq!(turtle.state.color, Vec3::new(0.5, 0.5, 0.0));
+
+        let mut turtle = Turtle::new().unwrap();
+        let heading = turtle.state.heading();
+        turtle.state.reverse();
+        assert_relative_eq!(turtle.state.heading(), -heading);
+        assert_relative_eq!(turtle.state.up(), Vec3::z());
+    }
+
+    #[test]
+    fn test_sphere() {
+        let mut turtle = Turtle::new().unwrap();
+        turtle.state.mov(1.0);
+        let sphere = turtle.state.sphere(0.5).unwrap();
+        let center = turtle.state.position();
+        for t in sphere.chunks(3) {
+            let [a, b, c] = [t[0].pos, t[1].pos, t[2].pos].map(Vec3::from);
+            assert_relative_eq!(glm::distance(&a, &center), 0.5, epsilon = 1e-5);
+            let outward = glm::dot(&(b - a).cross(&(c - a)), &(a - center));
+            assert!(outward > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_cylinder() {
+        let mut turtle = Turtle::new().unwrap();
+        let n = turtle.defaults.shape_segments as usize;
+        let lstring = parse_lstring("@GsF+F@Gc(4)/(45)+F+F@Ge(6)F").unwrap();
+        let mut outputs = Vec::new();
+        let mut end = Vec3::zeros();
+        for element in &lstring {
+            outputs.push(turtle.interpret_element(element).unwrap());
+            if element.symbol == symbol::CYLINDER_END {
+                end = turtle.state.position();
+            }
+        }
+        let counts: Vec<_> = outputs.iter().map(|o| o.as_ref().map(Vec::len)).collect();
+        assert_eq!(
+            counts,
+            vec![
+                None,
+                None,
+                None,
+                None,
+                Some(4 * n * 6),
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(6 * n * 6),
+                Some(n * 6)
+            ]
+        );
+        // Every ring keeps its radius about the curve, even through a half turn and
+        // a roll, and the last ring ends where the turtle is
+        let size = turtle.defaults.size;
+        let sweep: Vec<_> = outputs[10]
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|v| Vec3::from(v.pos))
+            .collect();
+        for triangle in sweep.chunks(3) {
+            let area = (triangle[1] - triangle[0]).cross(&(triangle[2] - triangle[0]));
+            assert!(glm::length(&area) > 1e-9);
+        }
+        let last_ring = &sweep[sweep.len() - n * 6..];
+        assert!(last_ring
+            .iter()
+            .any(|v| (glm::distance(v, &end) - size).abs() < 1e-5));
+    }
+
+    #[test]
+    fn test_contour() {
+        let mut turtle = Turtle::new().unwrap();
+        let n = turtle.defaults.shape_segments as usize;
+        let square = Contour::new(vec![
+            Vec2::new(1.0, 1.0),
+            Vec2::new(-1.0, 1.0),
+            Vec2::new(-1.0, -1.0),
+            Vec2::new(1.0, -1.0),
+        ])
+        .unwrap();
+        turtle
+            .contours
+            .push(TurtleState::outlines(&square.resample(n)));
+        let lstring = parse_lstring("[@#(1)F]F").unwrap();
+        let mut tubes = Vec::new();
+        for element in &lstring {
+            if let Some(vertices) = turtle.interpret_element(element).unwrap() {
+                tubes.push(vertices);
+            }
+        }
+        assert_eq!(tubes[0].len(), tubes[1].len());
+        let size = turtle.defaults.size;
+        for v in &tubes[0] {
+            // On the sides of the square, facing out from them
+            let (x, z) = (v.pos[0] / size, v.pos[2] / size);
+            assert_relative_eq!(x.abs().max(z.abs()), 1.0, epsilon = 1e-5);
+            assert!(v.norm[0] * x + v.norm[2] * z > 0.0);
+            assert_relative_eq!(v.norm[1], 0.0);
+        }
+        for v in &tubes[1] {
+            let (x, z) = (v.pos[0] / size, v.pos[2] / size);
+            assert_relative_eq!(x.hypot(z), 1.0, epsilon = 1e-5);
+        }
+        match turtle.interpret_element(parse_lstring("@#(2)").unwrap().get(0).unwrap()) {
+            Err(Error::Config(_)) => (),
+            other => panic!("expected configuration error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_shared_rings() {
+        let mut turtle = Turtle::new().unwrap();
+        let n = turtle.defaults.shape_segments as usize;
+        let mesh = turtle.interpret(&parse_lstring("FFF").unwrap()).unwrap();
+        assert_eq!(mesh.indices().len(), 3 * n * 6);
+        assert_eq!(mesh.vertices().len(), 4 * n);
+    }
+
+    #[test]
+    fn test_caps() {
+        let mut turtle = Turtle::new().unwrap();
+        turtle.defaults.caps = true;
+        turtle.state.defaults.caps = true;
+        let n = turtle.defaults.shape_segments as usize;
+        let mesh = turtle.interpret(&parse_lstring("FFGF").unwrap()).unwrap();
+        let triangles = mesh.unindexed();
+        assert_eq!(triangles.len(), 3 * n * 6 + 4 * (n - 2) * 3);
+        // Two closed tubes: every edge is shared by exactly two triangles, one going
+        // each way round
+        let mut edges = HashMap::new();
+        for t in triangles.chunks(3) {
+            for i in 0..3 {
+                let edge = [t[i].pos, t[(i + 1) % 3].pos];
+                *edges.entry(edge.map(|p| p.map(f32::to_bits))).or_insert(0) += 1;
+            }
+        }
+        for ([a, b], count) in &edges {
+            assert_eq!(*count, 1);
+            assert_eq!(edges.get(&[*b, *a]), Some(&1));
+        }
+        // The caps face away from the tubes
+        let first_cap = &triangles[..(n - 2) * 3];
+        assert!(first_cap.iter().all(|v| v.norm == [0.0, -1.0, 0.0]));
+        for t in first_cap.chunks(3) {
+            let [a, b, c] = [t[0].pos, t[1].pos, t[2].pos].map(Vec3::from);
+            assert!((b - a).cross(&(c - a)).y < 0.0);
+        }
+    }
+
+    #[test]
+    fn test_bent_segments_are_welded() {
+        let mut turtle = Turtle::new().unwrap();
+        turtle.defaults.caps = true;
+        turtle.state.defaults.caps = true;
+        turtle.state.defaults.tropism = [1.0, 0.0, 0.0];
+        let n = turtle.defaults.shape_segments as usize;
+        let mesh = turtle
+            .interpret(&parse_lstring("@T(0.3)FFF").unwrap())
+            .unwrap();
+        // One tube with a cap at either end
+        assert_eq!(mesh.indices().len(), 3 * n * 6 + 2 * (n - 2) * 3);
+        assert_eq!(mesh.vertices().len(), 4 * n + 2 * n);
+    }
+
+    #[test]
+    fn test_lod() {
+        let mut turtle = Turtle::new().unwrap();
+        turtle.set_lod(Some(0.02));
+        let lstring = parse_lstring("F(1,0.02)!(0.01)F!(0.001)F").unwrap();
+        let mut counts = Vec::new();
+        for element in &lstring {
+            if let Some(vertices) = turtle.interpret_element(element).unwrap() {
+                counts.push(vertices.len() / 6);
+            }
+        }
+        // Full detail at the LOD radius, half at half of it and a triangular prism
+        // at the thinnest
+        assert_eq!(counts, vec![24, 12, 3]);
+
+        let meshes = Turtle::interpret_lods(&lstring, &[0.0, 0.02, 1.0]).unwrap();
+        let triangles: Vec<_> = meshes.iter().map(|m| m.indices().len() / 3).collect();
+        assert_eq!(triangles, vec![3 * 24 * 2, (24 + 12 + 3) * 2, 3 * 3 * 2]);
+
+        let interpret = |turtle: &mut Turtle| turtle.interpret(&lstring);
+        assert_eq!(Turtle::within_budget(1000, interpret).unwrap(), meshes[0]);
+        assert_eq!(Turtle::within_budget(100, interpret).unwrap(), meshes[1]);
+        assert_eq!(Turtle::within_budget(1, interpret).unwrap(), meshes[2]);
+    }
+
+    #[test]
+    fn test_lod_rings_are_joined() {
+        let mut turtle = Turtle::new().unwrap();
+        turtle.set_lod(Some(0.02));
+        let lstring = parse_lstring("F(1,0.02)F(1,0.01)F(1,0.001)F(1,0.001)").unwrap();
+        let mesh = turtle.interpret(&lstring).unwrap();
+        let mut edges = HashMap::new();
+        for t in mesh.unindexed().chunks(3) {
+            for i in 0..3 {
+                let mut edge = [t[i].pos, t[(i + 1) % 3].pos].map(|p| p.map(f32::to_bits));
+                edge.sort();
+                *edges.entry(edge).or_insert(0) += 1;
+            }
+        }
+        // Only the full start ring and the triangular end ring are left open
+        let n = turtle.defaults.shape_segments as usize;
+        let open = edges.values().filter(|&&count| count == 1).count();
+        assert_eq!(open, n + 3);
+        assert!(edges.values().all(|&count| count <= 2));
+    }
+
     #[test]
     fn test_draw() {
-        let mut turtle = Turtle::new();
+        let mut turtle = Turtle::new().unwrap();
         turtle.state.draw(0.5, None);
         println!("{:?}", turtle);
         assert_relative_eq!(turtle.state.position(), Vec3::new(0.0, 0.5, 0.0));